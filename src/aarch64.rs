@@ -0,0 +1,33 @@
+//! AArch64 specific page table structures.
+
+use crate::{page_table_entry::aarch64::A64PTE, PageTable64, PagingMetaData, TrackedTables};
+
+/// Metadata of AArch64 page tables (4K granule, 4 levels).
+#[derive(Clone, Copy)]
+pub struct A64PagingMetaData;
+
+impl const PagingMetaData for A64PagingMetaData {
+    const LEVELS: usize = 4;
+    const PA_MAX_BITS: usize = 48;
+    const VA_MAX_BITS: usize = 48;
+
+    #[inline]
+    fn vaddr_is_valid(vaddr: usize) -> bool {
+        // TTBR0_EL1 covers the low half and TTBR1_EL1 the high half, so the
+        // top bits must be all zeros or all ones.
+        let top_bits = vaddr >> Self::VA_MAX_BITS;
+        top_bits == 0 || top_bits == 0xffff
+    }
+
+    #[inline]
+    fn root_register(root_paddr: usize, asid: u16) -> usize {
+        // ASID in bits 48..64 of TTBR0_EL1 (TCR_EL1.AS = 1 for 16-bit ASIDs)
+        ((asid as u64) << 48 | root_paddr as u64) as usize
+    }
+}
+
+/// AArch64 VMSAv8-64 translation table.
+///
+/// The kernel must program `MAIR_EL1` with
+/// [`MemAttr::MAIR_VALUE`](crate::MemAttr::MAIR_VALUE) before using it.
+pub type A64PageTable<I, S = TrackedTables<A64PTE>> = PageTable64<A64PagingMetaData, A64PTE, I, S>;
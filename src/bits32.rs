@@ -0,0 +1,351 @@
+use core::marker::PhantomData;
+
+use memory_addr::{PhysAddr, VirtAddr};
+
+use crate::{
+    walk::PageTableWalk, GenericPTE, MapRegionError, MappingFlags, PageSize, PagingError, PagingIf,
+    PagingMetaData, PagingResult, RegionError, TableStore, TlbFlushToken, TrackedTables,
+};
+
+#[cfg(test)]
+mod tests;
+
+/// A generic page table struct for 32-bit platform.
+///
+/// It also tracks all intermediate level tables. They will be deallocated
+/// When the [`PageTable32`] itself is dropped.
+///
+/// Addresses that are not valid according to
+/// [`PagingMetaData::vaddr_range_is_valid`] and
+/// [`PagingMetaData::paddr_is_valid`] are rejected with
+/// [`PagingError::InvalidAddress`] before any entry is changed.
+pub struct PageTable32<M: PagingMetaData, PTE: GenericPTE, IF: PagingIf<PTE>> {
+    root_paddr: PhysAddr,
+    tables: TrackedTables<PTE>,
+    alloc: IF,
+    _phantom: PhantomData<(M, PTE)>,
+}
+
+impl<M: PagingMetaData, PTE: GenericPTE, IF: PagingIf<PTE>> PageTable32<M, PTE, IF> {
+    /// Creates a new page table instance or returns the error.
+    ///
+    /// It will allocate a new page for the root page table.
+    pub fn try_new() -> PagingResult<Self>
+    where
+        IF: Default,
+    {
+        Self::try_new_in(IF::default())
+    }
+
+    /// Creates a new page table instance that allocates its tables with
+    /// `alloc`, or returns the error.
+    ///
+    /// It will allocate a new page for the root page table.
+    pub fn try_new_in(mut alloc: IF) -> PagingResult<Self> {
+        let mut tables = TrackedTables::default();
+        let root_paddr = tables.alloc_table(&mut alloc)?;
+        Ok(Self {
+            root_paddr,
+            tables,
+            alloc,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// Returns the allocator of the tables.
+    pub const fn allocator(&self) -> &IF {
+        &self.alloc
+    }
+
+    /// Returns the physical address of the root page table.
+    pub const fn root_paddr(&self) -> PhysAddr {
+        self.root_paddr
+    }
+
+    /// Returns the value to write into the register that holds the root page
+    /// table (e.g. `satp`, `CR3` or `TTBR0_EL1`) to activate this page table
+    /// with the address space identifier `asid`.
+    ///
+    /// See [`PagingMetaData::root_register`] for the encoding.
+    pub fn root_register(&self, asid: u16) -> usize {
+        M::root_register(self.root_paddr().as_usize(), asid)
+    }
+
+    /// Maps a virtual page to a physical frame with the given `page_size`
+    /// and mapping `flags`.
+    ///
+    /// The virtual page starts with `vaddr`, amd the physical frame starts with
+    /// `target`. If the addresses is not aligned to the page size, they will be
+    /// aligned down automatically.
+    ///
+    /// The software-defined flags in `flags` must fit in the entries (see
+    /// [`GenericPTE::SOFTWARE_BITS`]), otherwise it returns
+    /// [`Err(PagingError::InvalidPermission)`](PagingError::InvalidPermission).
+    /// The same applies to the other operations that set flags.
+    ///
+    /// Returns a token to flush the TLB entry of the page, or
+    /// [`Err(PagingError::AlreadyMapped)`](PagingError::AlreadyMapped) if the
+    /// mapping is already present.
+    pub fn map(
+        &mut self,
+        vaddr: VirtAddr,
+        target: PhysAddr,
+        page_size: PageSize,
+        flags: MappingFlags,
+    ) -> PagingResult<TlbFlushToken> {
+        self.map_page(vaddr, target, page_size, flags)
+    }
+
+    /// Unmaps the mapping starts with `vaddr`.
+    ///
+    /// Intermediate tables that become empty are reclaimed, in which case the
+    /// returned token flushes the entire TLB, as the hardware may cache them.
+    ///
+    /// The entry is cleared atomically, and the value it had right before is
+    /// returned together with the page size and a token to flush the TLB
+    /// entry of the page. The unmapped frame is at its
+    /// [`paddr`](GenericPTE::paddr), and bits set by the MMU concurrently are
+    /// not lost.
+    ///
+    /// Returns [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the
+    /// mapping is not present.
+    pub fn unmap(&mut self, vaddr: VirtAddr) -> PagingResult<(PTE, PageSize, TlbFlushToken)> {
+        self.unmap_page(vaddr)
+    }
+
+    /// Query the result of the mapping starts with `vaddr`.
+    ///
+    /// Returns the physical address of the target frame, mapping flags, and
+    /// the page size.
+    ///
+    /// Returns [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the
+    /// mapping is not present.
+    pub fn query(&self, vaddr: VirtAddr) -> PagingResult<(PhysAddr, MappingFlags, PageSize)> {
+        self.query_page(vaddr)
+    }
+
+    /// Updates the target or flags of the mapping starts with `vaddr`. If the
+    /// corresponding argument is `None`, it will not be updated.
+    ///
+    /// The entry is updated atomically, and the value it had right before is
+    /// returned together with the page size of the mapping and a token to
+    /// flush the TLB entry of the page, so that a page that became dirty
+    /// during the change can be observed.
+    ///
+    /// Returns [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the
+    /// mapping is not present.
+    pub fn update(
+        &mut self,
+        vaddr: VirtAddr,
+        paddr: Option<PhysAddr>,
+        flags: Option<MappingFlags>,
+    ) -> PagingResult<(PTE, PageSize, TlbFlushToken)> {
+        self.update_page(vaddr, paddr, flags)
+    }
+
+    /// Map a contiguous virtual memory region to a contiguous physical memory
+    /// region with the given mapping `flags`.
+    ///
+    /// The virtual and physical memory regions start with `vaddr` and `paddr`
+    /// respectively. The region size is `size`. The addresses and `size` must
+    /// be aligned to 4K, otherwise it will return [`Err(PagingError::NotAligned)`].
+    ///
+    /// When `allow_huge` is true, it will try to map the region with huge pages
+    /// if possible. Otherwise, it will map the region with 4K pages.
+    ///
+    /// If it fails half way, the pages mapped so far are unmapped and the
+    /// intermediate tables allocated for them are reclaimed, so the page table
+    /// is left as it was before the call. The returned [`MapRegionError`]
+    /// reports the progress and carries a token to flush the TLB entries of
+    /// those pages and tables.
+    ///
+    /// Returns a token to flush the TLB entries of the mapped pages.
+    ///
+    /// [`Err(PagingError::NotAligned)`]: PagingError::NotAligned
+    #[allow(clippy::result_large_err)] // the error carries a token like the `Ok` value
+    pub fn map_region(
+        &mut self,
+        vaddr: VirtAddr,
+        paddr: PhysAddr,
+        size: usize,
+        flags: MappingFlags,
+        allow_huge: bool,
+    ) -> Result<TlbFlushToken, MapRegionError> {
+        if !vaddr.is_aligned(PageSize::Size4K)
+            || !paddr.is_aligned(PageSize::Size4K)
+            || !memory_addr::is_aligned(size, PageSize::Size4K.into())
+        {
+            return Err(PagingError::NotAligned.into());
+        }
+        Self::check_vaddr(vaddr, size)?;
+        Self::check_paddr(paddr, size)?;
+        Self::check_flags(flags)?;
+        trace!(
+            "map_region({:#x}): [{:#x}, {:#x}) -> [{:#x}, {:#x}) {:?}",
+            self.root_paddr(),
+            vaddr,
+            vaddr.as_usize().wrapping_add(size),
+            paddr,
+            paddr.as_usize().wrapping_add(size),
+            flags,
+        );
+        let start = vaddr;
+        let mut flush = TlbFlushToken::empty();
+        // offsets in the region, so that the addresses do not overflow at the
+        // top of the address space
+        let mut mapped = 0;
+        while mapped < size {
+            let (vaddr, paddr) = (start + mapped, paddr + mapped);
+            let page_size = Self::region_page_size(vaddr, paddr, size - mapped, allow_huge);
+            match self.map(vaddr, paddr, page_size, flags) {
+                Ok(page_flush) => flush.merge(page_flush),
+                Err(e) => {
+                    error!(
+                        "failed to map page: {:#x?}({:?}) -> {:#x?}, {:?}, rolling back {:#x} bytes",
+                        vaddr,
+                        page_size,
+                        paddr,
+                        e,
+                        mapped
+                    );
+                    let rollback = self.rollback_map_region(start, vaddr, &mut flush);
+                    return Err(MapRegionError {
+                        error: e,
+                        mapped,
+                        rollback_error: rollback.err(),
+                        flush,
+                    });
+                }
+            }
+            mapped += page_size as usize;
+        }
+        Ok(flush)
+    }
+
+    /// Unmap a contiguous virtual memory region.
+    ///
+    /// The region starts with `vaddr` and its size is `size`. They must be
+    /// aligned to 4K, otherwise it will return [`Err(PagingError::NotAligned)`].
+    /// Huge pages that are only partially covered by the region are split into
+    /// smaller pages first, so the remaining part stays mapped.
+    ///
+    /// The region must be mapped before using [`PageTable32::map_region`], or
+    /// unexpected behaviors may occur.
+    ///
+    /// Returns a token to flush the TLB entries of the unmapped pages, or the
+    /// entire TLB if intermediate tables that become empty are reclaimed. If it
+    /// fails half way, the pages unmapped so far stay unmapped, and the
+    /// returned [`RegionError`] carries the token to flush them.
+    ///
+    /// [`Err(PagingError::NotAligned)`]: PagingError::NotAligned
+    #[allow(clippy::result_large_err)] // the error carries a token like the `Ok` value
+    pub fn unmap_region(
+        &mut self,
+        vaddr: VirtAddr,
+        size: usize,
+    ) -> Result<TlbFlushToken, RegionError> {
+        if !vaddr.is_aligned(PageSize::Size4K)
+            || !memory_addr::is_aligned(size, PageSize::Size4K.into())
+        {
+            return Err(PagingError::NotAligned.into());
+        }
+        Self::check_vaddr(vaddr, size)?;
+        trace!(
+            "unmap_region({:#x}) [{:#x}, {:#x})",
+            self.root_paddr(),
+            vaddr,
+            vaddr.as_usize().wrapping_add(size),
+        );
+        let start = vaddr;
+        let mut flush = TlbFlushToken::empty();
+        let mut done = 0;
+        while done < size {
+            let vaddr = start + done;
+            let page_size = match self.unmap_in_region(vaddr, size - done) {
+                Ok((page_size, page_flush)) => {
+                    flush.merge(page_flush);
+                    page_size
+                }
+                Err(error) => {
+                    error!("failed to unmap page: {:#x?}, {:?}", vaddr, error);
+                    return Err(RegionError { error, done, flush });
+                }
+            };
+            done += page_size as usize;
+        }
+        Ok(flush)
+    }
+
+    /// Walk the page table recursively.
+    ///
+    /// When reaching the leaf page table, call `func` on the current page table
+    /// entry. The max number of enumerations in one table is limited by `limit`.
+    ///
+    /// The arguments of `func` are:
+    /// - Current level (starts with `0`): `usize`
+    /// - The index of the entry in the current-level table: `usize`
+    /// - The virtual address that is mapped to the entry: [`VirtAddr`]
+    /// - The reference of the entry: [`&PTE`](GenericPTE)
+    pub fn walk<F>(&self, limit: usize, func: &F) -> PagingResult
+    where
+        F: Fn(usize, usize, VirtAddr, &PTE),
+    {
+        self.walk_recursive(self.root_paddr(), 0, VirtAddr::from(0), limit, func)
+    }
+}
+
+// Private implements.
+impl<M: PagingMetaData, PTE: GenericPTE, IF: PagingIf<PTE>> PageTable32<M, PTE, IF> {
+    /// Unmaps the page at `vaddr`, which is the start of the remaining `size`
+    /// bytes of a region. A huge page that is not entirely in them is split
+    /// into smaller pages first.
+    ///
+    /// Returns the page size of the unmapped page and a token to flush it.
+    fn unmap_in_region(
+        &mut self,
+        vaddr: VirtAddr,
+        size: usize,
+    ) -> PagingResult<(PageSize, TlbFlushToken)> {
+        loop {
+            let (paddr, level) = self.leaf_table_paddr(vaddr)?;
+            let page_size = Self::level_page_size(level);
+            if vaddr.is_aligned(page_size) && page_size as usize <= size {
+                break;
+            }
+            self.split_huge(paddr, Self::entry_index(vaddr, level), level)?;
+        }
+        let (_, page_size, flush) = self.unmap(vaddr)?;
+        Ok((page_size, flush))
+    }
+}
+
+impl<M: PagingMetaData, PTE: GenericPTE, IF: PagingIf<PTE>> PageTableWalk
+    for PageTable32<M, PTE, IF>
+{
+    type Meta = M;
+    type Entry = PTE;
+
+    const INDEX_BITS: usize = 10;
+
+    fn root_table(&self) -> PhysAddr {
+        self.root_paddr
+    }
+
+    fn table_of(&self, paddr: PhysAddr) -> &[PTE::Atomic] {
+        self.tables.table(&self.alloc, paddr)
+    }
+
+    fn alloc_table(&mut self) -> PagingResult<PhysAddr> {
+        self.tables.alloc_table(&mut self.alloc)
+    }
+
+    fn dealloc_table(&mut self, paddr: PhysAddr) {
+        self.tables.dealloc_table(&mut self.alloc, paddr);
+    }
+}
+
+impl<M: PagingMetaData, PTE: GenericPTE, IF: PagingIf<PTE>> Drop for PageTable32<M, PTE, IF> {
+    fn drop(&mut self) {
+        self.free_tables_recursive(self.root_paddr(), 0, VirtAddr::from(0));
+    }
+}
@@ -0,0 +1,100 @@
+use alloc::sync::Arc;
+
+use super::PageTable32;
+use crate::mock::{pa, va, MockIf, Stats};
+use crate::{MappingFlags, PageSize, PagingError, PagingMetaData, Rv32PTE, Sv32MetaData};
+
+const RW: MappingFlags = MappingFlags::READ.union(MappingFlags::WRITE);
+const AD: MappingFlags = MappingFlags::ACCESSED.union(MappingFlags::DIRTY);
+
+type MockPageTable = PageTable32<Sv32MetaData, Rv32PTE, MockIf<Rv32PTE>>;
+
+fn new_page_table() -> (MockPageTable, Arc<Stats>) {
+    let stats = Stats::new();
+    let pt = MockPageTable::try_new_in(MockIf::new(stats.clone())).unwrap();
+    (pt, stats)
+}
+
+#[test]
+fn sv32_pages() {
+    let (mut pt, stats) = new_page_table();
+    pt.map_region(va(0x3f_f000), pa(0x2_003f_f000), 0x40_1000, RW, true)
+        .unwrap()
+        .ignore();
+    assert_eq!(
+        pt.query(va(0x3f_f000)).unwrap(),
+        (pa(0x2_003f_f000), RW | AD, PageSize::Size4K)
+    );
+    // the 4M page in the middle
+    assert_eq!(
+        pt.query(va(0x40_1000)).unwrap(),
+        (pa(0x2_0040_1000), RW | AD, PageSize::Size4M)
+    );
+    assert_eq!(stats.live(), 2);
+
+    pt.unmap_region(va(0x3f_f000), 0x2000).unwrap().ignore();
+    assert!(matches!(
+        pt.query(va(0x40_0000)),
+        Err(PagingError::NotMapped)
+    ));
+    assert_eq!(
+        pt.query(va(0x40_1000)).unwrap(),
+        (pa(0x2_0040_1000), RW | AD, PageSize::Size4K)
+    );
+    assert_eq!(stats.live(), 2);
+
+    pt.unmap_region(va(0x40_1000), 0x3f_f000).unwrap().ignore();
+    assert_eq!(stats.live(), 1);
+}
+
+#[test]
+fn sv32_unmap_region_errors() {
+    let (mut pt, stats) = new_page_table();
+    assert!(matches!(
+        pt.unmap_region(va(0x800), 0x1000),
+        Err(e) if matches!(e.error, PagingError::NotAligned)
+    ));
+
+    pt.map_region(va(0x1000), pa(0x1000), 0x2000, RW, false)
+        .unwrap()
+        .ignore();
+    let err = pt.unmap_region(va(0x1000), 0x3000).unwrap_err();
+    assert!(matches!(err.error, PagingError::NotMapped));
+    assert_eq!(err.done, 0x2000);
+    assert!(err.flush.is_all());
+    assert_eq!(stats.live(), 1);
+}
+
+#[test]
+fn sv32_top_of_address_space() {
+    let (mut pt, stats) = new_page_table();
+    pt.map_region(va(0xffc0_0000), pa(0x40_0000), 0x40_0000, RW, true)
+        .unwrap()
+        .ignore();
+    assert_eq!(
+        pt.query(va(0xffff_ffff)).unwrap(),
+        (pa(0x7f_ffff), RW | AD, PageSize::Size4M)
+    );
+    assert!(matches!(
+        pt.map_region(va(0xffff_f000), pa(0x1000), 0x2000, RW, false),
+        Err(e) if matches!(e.error, PagingError::InvalidAddress(_))
+    ));
+
+    pt.unmap_region(va(0xffff_f000), 0x1000).unwrap().ignore();
+    assert_eq!(pt.query(va(0xffff_e000)).unwrap().2, PageSize::Size4K);
+    pt.unmap_region(va(0xffc0_0000), 0x3f_f000)
+        .unwrap()
+        .ignore();
+    assert_eq!(stats.live(), 1);
+}
+
+#[test]
+fn sv32_root_register() {
+    let (pt, _) = new_page_table();
+    assert_eq!(pt.root_register(0x1ff), 1 << 31 | 0x1ff << 22 | 1);
+    // ASIDs are 9 bits wide
+    assert_eq!(
+        Sv32MetaData::root_register(0xffff_f000, 0xfe01),
+        1 << 31 | 1 << 22 | 0xf_ffff
+    );
+}
@@ -1,65 +1,226 @@
 extern crate alloc;
 
-use alloc::{boxed::Box, collections::BTreeMap};
-use core::marker::PhantomData;
+use alloc::vec::Vec;
+use core::{
+    marker::PhantomData,
+    ops::{Range, RangeInclusive},
+};
 
 use memory_addr::{PhysAddr, VirtAddr};
 
 use crate::{
-    GenericPTE, MappingFlags, NotLeafPage, PageSize, PagingError, PagingIf, PagingMetaData,
-    PagingResult,
+    walk::PageTableWalk, GenericPTE, MapRegionError, MappingFlags, PageSize, PagingError,
+    PagingMetaData, PagingResult, RegionError, TableStore, TlbFlushToken, TrackedTables,
 };
 
-pub const ENTRY_COUNT: usize = 512;
-
-const fn p4_index(vaddr: VirtAddr) -> usize {
-    (vaddr.as_usize() >> (12 + 27)) & (ENTRY_COUNT - 1)
-}
+mod cursor;
+#[cfg(test)]
+mod tests;
 
-const fn p3_index(vaddr: VirtAddr) -> usize {
-    (vaddr.as_usize() >> (12 + 18)) & (ENTRY_COUNT - 1)
-}
+pub use cursor::PageTableCursor;
 
-const fn p2_index(vaddr: VirtAddr) -> usize {
-    (vaddr.as_usize() >> (12 + 9)) & (ENTRY_COUNT - 1)
-}
-
-const fn p1_index(vaddr: VirtAddr) -> usize {
-    (vaddr.as_usize() >> 12) & (ENTRY_COUNT - 1)
-}
+pub const ENTRY_COUNT: usize = 512;
 
 /// A generic page table struct for 64-bit platform.
 ///
-/// It also tracks all intermediate level tables. They will be deallocated
-/// When the [`PageTable64`] itself is dropped.
-pub struct PageTable64<M: PagingMetaData, PTE: GenericPTE, IF: PagingIf<PTE>> {
+/// It also owns all intermediate level tables, which are kept in the
+/// [`TableStore`] `S`. They will be deallocated When the [`PageTable64`]
+/// itself is dropped, or when they become empty after unmapping. Tables shared
+/// from another page table by [`PageTable64::try_new_with_shared`] are not
+/// owned.
+///
+/// By default the tables are [`TrackedTables`] allocated with
+/// [`PagingIf`](crate::PagingIf). Kernels with a linear mapping of the
+/// physical memory can use [`LinearTables`] with
+/// [`LinearPagingIf`](crate::LinearPagingIf) instead.
+///
+/// Addresses that are not valid according to
+/// [`PagingMetaData::vaddr_range_is_valid`] and
+/// [`PagingMetaData::paddr_is_valid`] are rejected with
+/// [`PagingError::InvalidAddress`] before any entry is changed.
+pub struct PageTable64<
+    M: PagingMetaData,
+    PTE: GenericPTE,
+    IF,
+    S: TableStore<PTE, IF> = TrackedTables<PTE>,
+> {
     root_paddr: PhysAddr,
-    intrm_tables: BTreeMap<PhysAddr, Box<dyn NotLeafPage<PTE>>>,
-    _phantom: PhantomData<(M, PTE, IF)>,
+    tables: S,
+    /// Whether reclaimed intermediate tables are kept in `reclaimed_tables`
+    /// instead of being released immediately.
+    defer_reclaim: bool,
+    reclaimed_tables: Vec<PhysAddr>,
+    /// Whether new leaf entries are created with the accessed and dirty bits
+    /// clear.
+    track_access: bool,
+    /// Indices of the root table entries that alias the tables of another
+    /// page table.
+    shared: Range<usize>,
+    /// Bitmap of the indices of the root table entries whose tables are
+    /// shared with other page tables, which are never reclaimed.
+    exported: [u64; ENTRY_COUNT / 64],
+    alloc: IF,
+    _phantom: PhantomData<(M, PTE)>,
 }
 
-impl<M: PagingMetaData, PTE: GenericPTE, IF: PagingIf<PTE>> PageTable64<M, PTE, IF> {
+impl<M: PagingMetaData, PTE: GenericPTE, IF, S: TableStore<PTE, IF>> PageTable64<M, PTE, IF, S> {
     /// Creates a new page table instance or returns the error.
     ///
     /// It will allocate a new page for the root page table.
-    pub fn try_new() -> PagingResult<Self> {
-        let page = Self::alloc_table()?;
+    pub fn try_new() -> PagingResult<Self>
+    where
+        IF: Default,
+    {
+        Self::try_new_in(IF::default())
+    }
+
+    /// Creates a new page table instance that allocates its tables with
+    /// `alloc`, or returns the error.
+    ///
+    /// It will allocate a new page for the root page table.
+    pub fn try_new_in(mut alloc: IF) -> PagingResult<Self> {
+        let mut tables = S::default();
+        let root_paddr = tables.alloc_table(&mut alloc)?;
         Ok(Self {
-            root_paddr: page.phys_addr(),
-            intrm_tables: {
-                let mut map = BTreeMap::new();
-                map.insert(page.phys_addr(), page);
-                map
-            },
+            root_paddr,
+            tables,
+            defer_reclaim: false,
+            reclaimed_tables: Vec::new(),
+            track_access: false,
+            shared: 0..0,
+            exported: [0; ENTRY_COUNT / 64],
+            alloc,
             _phantom: PhantomData,
         })
     }
 
+    /// Creates a new page table instance whose mappings in `range` are shared
+    /// with `kernel`, or returns the error.
+    ///
+    /// The root table entries that map `range` point to the same intermediate
+    /// tables as those of `kernel`. These tables are still owned by `kernel`,
+    /// and are not freed when the new page table is dropped. The mappings in
+    /// `range` cannot be accessed through the new page table, the operations
+    /// on them return [`Err(PagingError::SharedMapping)`].
+    ///
+    /// The tables pointed to by the root table entries of `kernel` in `range`
+    /// are allocated here if they do not exist yet, and are never reclaimed or
+    /// collapsed afterwards, so the root table entries stay the same in all
+    /// page tables. `kernel` must outlive the page tables that share with it.
+    ///
+    /// The start of `range` and the address after its end must be aligned to
+    /// the size mapped by a root table entry, otherwise it will return
+    /// [`Err(PagingError::NotAligned)`]. An empty range, or a range with
+    /// addresses not valid according to [`PagingMetaData::vaddr_is_valid`],
+    /// returns [`Err(PagingError::InvalidAddress)`].
+    ///
+    /// Huge pages mapped by the root table entries of `kernel` in `range` are
+    /// shared as they are, and must not be unmapped while they are shared.
+    /// The operations on `kernel` that would split them return
+    /// [`Err(PagingError::SharedMapping)`].
+    ///
+    /// [`Err(PagingError::SharedMapping)`]: PagingError::SharedMapping
+    /// [`Err(PagingError::NotAligned)`]: PagingError::NotAligned
+    /// [`Err(PagingError::InvalidAddress)`]: PagingError::InvalidAddress
+    pub fn try_new_with_shared(
+        kernel: &mut Self,
+        range: RangeInclusive<VirtAddr>,
+    ) -> PagingResult<Self>
+    where
+        IF: Default,
+    {
+        Self::try_new_with_shared_in(kernel, range, IF::default())
+    }
+
+    /// Creates a new page table instance that allocates its tables with
+    /// `alloc`, and whose mappings in `range` are shared with `kernel`.
+    ///
+    /// See [`PageTable64::try_new_with_shared`] for details.
+    pub fn try_new_with_shared_in(
+        kernel: &mut Self,
+        range: RangeInclusive<VirtAddr>,
+        alloc: IF,
+    ) -> PagingResult<Self> {
+        let (start, end) = (*range.start(), *range.end());
+        if range.is_empty() {
+            return Err(PagingError::InvalidAddress(end.as_usize()));
+        }
+        for vaddr in [start, end] {
+            if !M::vaddr_is_valid(vaddr.as_usize()) {
+                return Err(PagingError::InvalidAddress(vaddr.as_usize()));
+            }
+        }
+        let root_size = 1 << Self::level_shift(0);
+        if !memory_addr::is_aligned(start.as_usize(), root_size)
+            || !memory_addr::is_aligned(end.as_usize().wrapping_add(1), root_size)
+        {
+            return Err(PagingError::NotAligned);
+        }
+        let shared = Self::entry_index(start, 0)..Self::entry_index(end, 0) + 1;
+        kernel.export_root_entries(shared.clone())?;
+        let mut pt = Self::try_new_in(alloc)?;
+        for i in shared.clone() {
+            let entry = kernel.entry_at(kernel.root_paddr(), i);
+            PTE::store(&pt.table_of(pt.root_paddr())[i], entry);
+        }
+        pt.shared = shared;
+        Ok(pt)
+    }
+
+    /// Returns the allocator of the tables.
+    pub const fn allocator(&self) -> &IF {
+        &self.alloc
+    }
+
     /// Returns the physical address of the root page table.
     pub const fn root_paddr(&self) -> PhysAddr {
         self.root_paddr
     }
 
+    /// Returns the value to write into the register that holds the root page
+    /// table (e.g. `satp`, `CR3` or `TTBR0_EL1`) to activate this page table
+    /// with the address space identifier `asid`.
+    ///
+    /// See [`PagingMetaData::root_register`] for the encoding.
+    pub fn root_register(&self, asid: u16) -> usize {
+        M::root_register(self.root_paddr().as_usize(), asid)
+    }
+
+    /// Sets whether intermediate tables that become empty after unmapping are
+    /// released immediately (the default), or kept until
+    /// [`PageTable64::release_reclaimed_tables`] is called.
+    ///
+    /// Deferring is needed when the hardware may still cache the reclaimed
+    /// tables, so they must not be reused before the TLB is flushed.
+    pub fn set_deferred_reclaim(&mut self, defer: bool) {
+        self.defer_reclaim = defer;
+        if !defer {
+            self.release_reclaimed_tables();
+        }
+    }
+
+    /// Sets whether the accessed and dirty bits of the pages mapped afterwards
+    /// start clear, so that accesses to them can be found by
+    /// [`PageTable64::test_and_clear_accessed`] and
+    /// [`PageTable64::test_and_clear_dirty`].
+    ///
+    /// By default the bits are set when a page is mapped, as required by the
+    /// MMUs that fault on pages with the bits clear instead of setting them.
+    /// When tracking is enabled, changing the flags of a page keeps its bits.
+    pub fn set_access_tracking(&mut self, enable: bool) {
+        self.track_access = enable;
+    }
+
+    /// Releases the intermediate tables reclaimed since the last call.
+    ///
+    /// It should be called after the TLB is flushed when deferred reclaim is
+    /// enabled by [`PageTable64::set_deferred_reclaim`].
+    pub fn release_reclaimed_tables(&mut self) {
+        for paddr in core::mem::take(&mut self.reclaimed_tables) {
+            self.dealloc_table(paddr);
+        }
+    }
+
     /// Maps a virtual page to a physical frame with the given `page_size`
     /// and mapping `flags`.
     ///
@@ -67,35 +228,43 @@ impl<M: PagingMetaData, PTE: GenericPTE, IF: PagingIf<PTE>> PageTable64<M, PTE,
     /// `target`. If the addresses is not aligned to the page size, they will be
     /// aligned down automatically.
     ///
-    /// Returns [`Err(PagingError::AlreadyMapped)`](PagingError::AlreadyMapped)
-    /// if the mapping is already present.
+    /// The software-defined flags in `flags` must fit in the entries (see
+    /// [`GenericPTE::SOFTWARE_BITS`]), otherwise it returns
+    /// [`Err(PagingError::InvalidPermission)`](PagingError::InvalidPermission).
+    /// The same applies to the other operations that set flags.
+    ///
+    /// Returns a token to flush the TLB entry of the page,
+    /// [`Err(PagingError::UnsupportedPageSize)`] if pages of `page_size`
+    /// cannot be mapped, or
+    /// [`Err(PagingError::AlreadyMapped)`](PagingError::AlreadyMapped) if the
+    /// mapping is already present.
+    ///
+    /// [`Err(PagingError::UnsupportedPageSize)`]: PagingError::UnsupportedPageSize
     pub fn map(
         &mut self,
         vaddr: VirtAddr,
         target: PhysAddr,
         page_size: PageSize,
         flags: MappingFlags,
-    ) -> PagingResult {
-        let entry = self.get_entry_mut_or_create(vaddr, page_size)?;
-        if !entry.is_unused() {
-            return Err(PagingError::AlreadyMapped);
-        }
-        *entry = GenericPTE::new_page(target.align_down(page_size), flags, page_size.is_huge());
-        Ok(())
+    ) -> PagingResult<TlbFlushToken> {
+        self.map_page(vaddr, target, page_size, flags)
     }
 
     /// Unmaps the mapping starts with `vaddr`.
     ///
+    /// Intermediate tables that become empty are reclaimed, in which case the
+    /// returned token flushes the entire TLB, as the hardware may cache them.
+    ///
+    /// The entry is cleared atomically, and the value it had right before is
+    /// returned together with the page size and a token to flush the TLB
+    /// entry of the page. The unmapped frame is at its
+    /// [`paddr`](GenericPTE::paddr), and bits set by the MMU concurrently are
+    /// not lost.
+    ///
     /// Returns [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the
     /// mapping is not present.
-    pub fn unmap(&mut self, vaddr: VirtAddr) -> PagingResult<(PhysAddr, PageSize)> {
-        let (entry, size) = self.get_entry_mut(vaddr)?;
-        if entry.is_unused() {
-            return Err(PagingError::NotMapped);
-        }
-        let paddr = entry.paddr();
-        entry.clear();
-        Ok((paddr, size))
+    pub fn unmap(&mut self, vaddr: VirtAddr) -> PagingResult<(PTE, PageSize, TlbFlushToken)> {
+        self.unmap_page(vaddr)
     }
 
     /// Query the result of the mapping starts with `vaddr`.
@@ -106,18 +275,16 @@ impl<M: PagingMetaData, PTE: GenericPTE, IF: PagingIf<PTE>> PageTable64<M, PTE,
     /// Returns [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the
     /// mapping is not present.
     pub fn query(&self, vaddr: VirtAddr) -> PagingResult<(PhysAddr, MappingFlags, PageSize)> {
-        let (entry, size) = self.get_entry_mut(vaddr)?;
-        if entry.is_unused() {
-            return Err(PagingError::NotMapped);
-        }
-        let off = vaddr.align_offset(size);
-        Ok((entry.paddr() + off, entry.flags(), size))
+        self.query_page(vaddr)
     }
 
     /// Updates the target or flags of the mapping starts with `vaddr`. If the
     /// corresponding argument is `None`, it will not be updated.
     ///
-    /// Returns the page size of the mapping.
+    /// The entry is updated atomically, and the value it had right before is
+    /// returned together with the page size of the mapping and a token to
+    /// flush the TLB entry of the page, so that a page that became dirty
+    /// during the change can be observed.
     ///
     /// Returns [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the
     /// mapping is not present.
@@ -126,15 +293,8 @@ impl<M: PagingMetaData, PTE: GenericPTE, IF: PagingIf<PTE>> PageTable64<M, PTE,
         vaddr: VirtAddr,
         paddr: Option<PhysAddr>,
         flags: Option<MappingFlags>,
-    ) -> PagingResult<PageSize> {
-        let (entry, size) = self.get_entry_mut(vaddr)?;
-        if let Some(paddr) = paddr {
-            entry.set_paddr(paddr);
-        }
-        if let Some(flags) = flags {
-            entry.set_flags(flags, size.is_huge());
-        }
-        Ok(size)
+    ) -> PagingResult<(PTE, PageSize, TlbFlushToken)> {
+        self.update_page(vaddr, paddr, flags)
     }
 
     /// Map a contiguous virtual memory region to a contiguous physical memory
@@ -147,7 +307,16 @@ impl<M: PagingMetaData, PTE: GenericPTE, IF: PagingIf<PTE>> PageTable64<M, PTE,
     /// When `allow_huge` is true, it will try to map the region with huge pages
     /// if possible. Otherwise, it will map the region with 4K pages.
     ///
+    /// If it fails half way, the pages mapped so far are unmapped and the
+    /// intermediate tables allocated for them are reclaimed, so the page table
+    /// is left as it was before the call. The returned [`MapRegionError`]
+    /// reports the progress and carries a token to flush the TLB entries of
+    /// those pages and tables.
+    ///
+    /// Returns a token to flush the TLB entries of the mapped pages.
+    ///
     /// [`Err(PagingError::NotAligned)`]: PagingError::NotAligned
+    #[allow(clippy::result_large_err)] // the error carries a token like the `Ok` value
     pub fn map_region(
         &mut self,
         vaddr: VirtAddr,
@@ -155,79 +324,501 @@ impl<M: PagingMetaData, PTE: GenericPTE, IF: PagingIf<PTE>> PageTable64<M, PTE,
         size: usize,
         flags: MappingFlags,
         allow_huge: bool,
-    ) -> PagingResult {
+    ) -> Result<TlbFlushToken, MapRegionError> {
         if !vaddr.is_aligned(PageSize::Size4K)
             || !paddr.is_aligned(PageSize::Size4K)
             || !memory_addr::is_aligned(size, PageSize::Size4K.into())
         {
-            return Err(PagingError::NotAligned);
+            return Err(PagingError::NotAligned.into());
+        }
+        Self::check_vaddr(vaddr, size)?;
+        Self::check_paddr(paddr, size)?;
+        Self::check_flags(flags)?;
+        if self.overlaps_shared(vaddr, size) {
+            return Err(PagingError::SharedMapping.into());
         }
         trace!(
             "map_region({:#x}): [{:#x}, {:#x}) -> [{:#x}, {:#x}) {:?}",
             self.root_paddr(),
             vaddr,
-            vaddr + size,
+            vaddr.as_usize().wrapping_add(size),
             paddr,
-            paddr + size,
+            paddr.as_usize().wrapping_add(size),
             flags,
         );
-        let mut vaddr = vaddr;
+        if size == 0 {
+            return Ok(TlbFlushToken::empty());
+        }
+        let start = vaddr;
+        let mut cursor = self.cursor_mut(vaddr..=vaddr + (size - 1))?;
+        let mut flush = TlbFlushToken::empty();
         let mut paddr = paddr;
         let mut size = size;
         while size > 0 {
-            let page_size = if allow_huge {
-                if vaddr.is_aligned(PageSize::Size1G)
-                    && paddr.is_aligned(PageSize::Size1G)
-                    && size >= PageSize::Size1G as usize
-                {
-                    PageSize::Size1G
-                } else if vaddr.is_aligned(PageSize::Size2M)
-                    && paddr.is_aligned(PageSize::Size2M)
-                    && size >= PageSize::Size2M as usize
-                {
-                    PageSize::Size2M
-                } else {
-                    PageSize::Size4K
+            let vaddr = cursor.vaddr();
+            let page_size = Self::region_page_size(vaddr, paddr, size, allow_huge);
+            match cursor.map_page(paddr, page_size, flags) {
+                Ok(page_flush) => flush.merge(page_flush),
+                Err(e) => {
+                    error!(
+                        "failed to map page: {:#x?}({:?}) -> {:#x?}, {:?}, rolling back {:#x} bytes",
+                        vaddr,
+                        page_size,
+                        paddr,
+                        e,
+                        vaddr.as_usize() - start.as_usize()
+                    );
+                    drop(cursor);
+                    let rollback = self.rollback_map_region(start, vaddr, &mut flush);
+                    return Err(MapRegionError {
+                        error: e,
+                        mapped: vaddr.as_usize() - start.as_usize(),
+                        rollback_error: rollback.err(),
+                        flush,
+                    });
                 }
-            } else {
-                PageSize::Size4K
-            };
-            self.map(vaddr, paddr, page_size, flags).inspect_err(|e| {
-                error!(
-                    "failed to map page: {:#x?}({:?}) -> {:#x?}, {:?}",
-                    vaddr, page_size, paddr, e
-                )
-            })?;
-            vaddr += page_size as usize;
+            }
             paddr += page_size as usize;
             size -= page_size as usize;
         }
-        Ok(())
+        Ok(flush)
     }
 
     /// Unmap a contiguous virtual memory region.
     ///
+    /// The region starts with `vaddr` and its size is `size`. They must be
+    /// aligned to 4K, otherwise it will return [`Err(PagingError::NotAligned)`].
+    /// Huge pages that are only partially covered by the region are split into
+    /// smaller pages first, so the remaining part stays mapped.
+    ///
     /// The region must be mapped before using [`PageTable64::map_region`], or
     /// unexpected behaviors may occur.
-    pub fn unmap_region(&mut self, vaddr: VirtAddr, size: usize) -> PagingResult {
+    ///
+    /// Returns a token to flush the TLB entries of the unmapped pages, or the
+    /// entire TLB if intermediate tables that become empty are reclaimed. If it
+    /// fails half way, the pages unmapped so far stay unmapped, and the
+    /// returned [`RegionError`] carries the token to flush them.
+    ///
+    /// [`Err(PagingError::NotAligned)`]: PagingError::NotAligned
+    #[allow(clippy::result_large_err)] // the error carries a token like the `Ok` value
+    pub fn unmap_region(
+        &mut self,
+        vaddr: VirtAddr,
+        size: usize,
+    ) -> Result<TlbFlushToken, RegionError> {
+        if !vaddr.is_aligned(PageSize::Size4K)
+            || !memory_addr::is_aligned(size, PageSize::Size4K.into())
+        {
+            return Err(PagingError::NotAligned.into());
+        }
+        Self::check_vaddr(vaddr, size)?;
+        if self.overlaps_shared(vaddr, size) {
+            return Err(PagingError::SharedMapping.into());
+        }
         trace!(
             "unmap_region({:#x}) [{:#x}, {:#x})",
             self.root_paddr(),
             vaddr,
-            vaddr + size,
+            vaddr.as_usize().wrapping_add(size),
         );
-        let mut vaddr = vaddr;
-        let mut size = size;
-        while size > 0 {
-            let (_, page_size) = self
-                .unmap(vaddr)
-                .inspect_err(|e| error!("failed to unmap page: {:#x?}, {:?}", vaddr, e))?;
-            assert!(vaddr.is_aligned(page_size));
-            assert!(page_size as usize <= size);
-            vaddr += page_size as usize;
-            size -= page_size as usize;
+        self.with_cursor(vaddr, size, |cursor, flush| {
+            while cursor.in_range() {
+                let vaddr = cursor.vaddr();
+                let (_, _, page_size) = cursor
+                    .query()
+                    .inspect_err(|e| error!("failed to unmap page: {:#x?}, {:?}", vaddr, e))?;
+                if cursor.crosses_range(page_size) {
+                    cursor.split_huge()?;
+                    continue;
+                }
+                flush.merge(cursor.unmap()?.2);
+            }
+            Ok(())
+        })
+    }
+
+    /// Changes the mapping flags of all the pages in a contiguous virtual
+    /// memory region.
+    ///
+    /// The region starts with `vaddr` and its size is `size`. They must be
+    /// aligned to 4K, otherwise it will return [`Err(PagingError::NotAligned)`].
+    /// Unmapped parts of the region are skipped, and huge pages that are only
+    /// partially covered by the region are split into smaller pages first.
+    ///
+    /// Returns a token to flush exactly the TLB entries of the pages whose
+    /// flags are changed. If it fails half way, e.g. when a table cannot be
+    /// allocated to split a huge page, the pages changed so far keep the new
+    /// flags, and the returned [`RegionError`] carries the token to flush them.
+    ///
+    /// [`Err(PagingError::NotAligned)`]: PagingError::NotAligned
+    #[allow(clippy::result_large_err)] // the error carries a token like the `Ok` value
+    pub fn protect_region(
+        &mut self,
+        vaddr: VirtAddr,
+        size: usize,
+        flags: MappingFlags,
+    ) -> Result<TlbFlushToken, RegionError> {
+        if !vaddr.is_aligned(PageSize::Size4K)
+            || !memory_addr::is_aligned(size, PageSize::Size4K.into())
+        {
+            return Err(PagingError::NotAligned.into());
         }
-        Ok(())
+        Self::check_vaddr(vaddr, size)?;
+        Self::check_flags(flags)?;
+        if self.overlaps_shared(vaddr, size) {
+            return Err(PagingError::SharedMapping.into());
+        }
+        trace!(
+            "protect_region({:#x}) [{:#x}, {:#x}) {:?}",
+            self.root_paddr(),
+            vaddr,
+            vaddr.as_usize().wrapping_add(size),
+            flags,
+        );
+        self.with_cursor(vaddr, size, |cursor, flush| {
+            while let Some(level) = cursor.find_mapped() {
+                if cursor.crosses_range(Self::level_page_size(level)) {
+                    cursor.split_huge()?;
+                    continue;
+                }
+                flush.merge(cursor.protect(flags)?.1);
+            }
+            Ok(())
+        })
+    }
+
+    /// Collapses the tables in a contiguous virtual memory region into huge
+    /// pages where possible.
+    ///
+    /// A table is collapsed if all its entries map physically contiguous pages
+    /// with identical flags, and the first page is aligned to the huge page
+    /// size. It is replaced by a single huge page and then freed. Tables of 4K
+    /// pages are collapsed first, so a table that becomes full of 2M pages can
+    /// be further collapsed into a 1G page. Pages larger than
+    /// [`PagingMetaData::MAX_PAGE_SIZE`] are never created.
+    ///
+    /// The region starts with `vaddr` and its size is `size`. They must be
+    /// aligned to 4K, otherwise it will return [`Err(PagingError::NotAligned)`].
+    /// Only huge pages that lie entirely in the region are created.
+    ///
+    /// Returns a token to flush the entire TLB if any table is collapsed, as
+    /// the hardware may still cache the freed tables.
+    ///
+    /// [`Err(PagingError::NotAligned)`]: PagingError::NotAligned
+    pub fn collapse_region(&mut self, vaddr: VirtAddr, size: usize) -> PagingResult<TlbFlushToken> {
+        if !vaddr.is_aligned(PageSize::Size4K)
+            || !memory_addr::is_aligned(size, PageSize::Size4K.into())
+        {
+            return Err(PagingError::NotAligned);
+        }
+        Self::check_vaddr(vaddr, size)?;
+        if self.overlaps_shared(vaddr, size) {
+            return Err(PagingError::SharedMapping);
+        }
+        trace!(
+            "collapse_region({:#x}) [{:#x}, {:#x})",
+            self.root_paddr(),
+            vaddr,
+            vaddr.as_usize().wrapping_add(size),
+        );
+        if size == 0 {
+            return Ok(TlbFlushToken::empty());
+        }
+        let last = vaddr.as_usize() + (size - 1);
+        let mut collapsed = false;
+        for level in (0..M::LEVELS - 1).rev() {
+            let Some(page_size) = Self::leaf_page_size(level) else {
+                continue;
+            };
+            let page_size = page_size as usize;
+            let mut start = vaddr.as_usize().checked_next_multiple_of(page_size);
+            while let Some(vaddr) =
+                start.filter(|&start| start <= last && last - start >= page_size - 1)
+            {
+                collapsed |= self.try_collapse(VirtAddr::from(vaddr), level);
+                start = vaddr.checked_add(page_size);
+            }
+        }
+        if collapsed {
+            Ok(TlbFlushToken::all())
+        } else {
+            Ok(TlbFlushToken::empty())
+        }
+    }
+
+    /// Creates a copy-on-write duplicate of the page table.
+    ///
+    /// All intermediate tables are duplicated, and the leaf entries are copied
+    /// so both page tables map the same physical frames. Writable user pages
+    /// are made read-only and marked with [`MappingFlags::COW`] in both page
+    /// tables, and a write to them should be handled by copying the page and
+    /// then calling [`PageTable64::resolve_cow_fault`].
+    ///
+    /// The mappings shared from another page table by
+    /// [`PageTable64::try_new_with_shared`] are shared by the new page table
+    /// as well.
+    ///
+    /// The tables of the new page table are allocated with a clone of the
+    /// allocator of `self`, and it reclaims tables and tracks accesses like
+    /// `self` (see [`PageTable64::set_deferred_reclaim`] and
+    /// [`PageTable64::set_access_tracking`]).
+    ///
+    /// Returns the new page table, and a token to flush the entire TLB of
+    /// `self`, as its writable pages become read-only. If it fails, pages of
+    /// `self` that have already been marked stay copy-on-write, so its TLB
+    /// must be flushed as well.
+    ///
+    /// Returns [`Err(PagingError::InvalidPermission)`](PagingError::InvalidPermission)
+    /// if the entries cannot store [`MappingFlags::COW`].
+    pub fn fork_cow(&mut self) -> PagingResult<(Self, TlbFlushToken)>
+    where
+        IF: Clone,
+    {
+        Self::check_flags(MappingFlags::COW)?;
+        let mut child = Self::try_new_in(self.alloc.clone())?;
+        child.shared = self.shared.clone();
+        child.defer_reclaim = self.defer_reclaim;
+        child.track_access = self.track_access;
+        let child_root = child.root_paddr();
+        self.fork_table(&mut child, self.root_paddr(), child_root, 0)?;
+        Ok((child, TlbFlushToken::all()))
+    }
+
+    /// Restores the write permission of the copy-on-write page that maps
+    /// `vaddr`, and maps it to `new_frame`.
+    ///
+    /// It should be called by the page fault handler after the page is copied
+    /// to `new_frame`. If the page is no longer shared with other page tables,
+    /// `new_frame` can be the original frame.
+    ///
+    /// Returns the page size of the mapping and a token to flush the TLB entry
+    /// of the page.
+    ///
+    /// Returns [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the
+    /// mapping is not present, or
+    /// [`Err(PagingError::InvalidPermission)`](PagingError::InvalidPermission)
+    /// if the page is not copy-on-write.
+    pub fn resolve_cow_fault(
+        &mut self,
+        vaddr: VirtAddr,
+        new_frame: PhysAddr,
+    ) -> PagingResult<(PageSize, TlbFlushToken)> {
+        Self::check_vaddr(vaddr, 1)?;
+        let (slot, size) = self.get_entry(vaddr)?;
+        Self::check_paddr(new_frame.align_down(size), size as usize)?;
+        let entry = PTE::load(slot);
+        if entry.is_unused() {
+            return Err(PagingError::NotMapped);
+        }
+        if !entry.flags().contains(MappingFlags::COW) {
+            return Err(PagingError::InvalidPermission);
+        }
+        PTE::fetch_update(slot, |mut entry| {
+            entry.set_paddr(new_frame.align_down(size));
+            // the page is being written
+            entry.set_flags(
+                (entry.flags() - MappingFlags::COW) | MappingFlags::WRITE | MappingFlags::DIRTY,
+                size.is_huge(),
+            );
+            entry
+        });
+        Ok((size, TlbFlushToken::page(vaddr)))
+    }
+
+    /// Sets the dirty bit of the writable page that maps `vaddr`, which
+    /// restores the write access to it if the MMU does not set the dirty bits
+    /// (see [`GenericPTE::HW_DIRTY`]).
+    ///
+    /// It should be called by the page fault handler when a writable page is
+    /// written for the first time, e.g. after
+    /// [`PageTable64::start_dirty_log`]. The write is then logged.
+    ///
+    /// Returns the page size of the mapping and a token to flush the TLB entry
+    /// of the page.
+    ///
+    /// Returns [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the
+    /// mapping is not present, or
+    /// [`Err(PagingError::InvalidPermission)`](PagingError::InvalidPermission)
+    /// if the page is not writable.
+    pub fn resolve_dirty_fault(
+        &mut self,
+        vaddr: VirtAddr,
+    ) -> PagingResult<(PageSize, TlbFlushToken)> {
+        Self::check_vaddr(vaddr, 1)?;
+        let (slot, size) = self.get_entry(vaddr)?;
+        let entry = PTE::load(slot);
+        if entry.is_unused() {
+            return Err(PagingError::NotMapped);
+        }
+        if !entry.flags().contains(MappingFlags::WRITE) {
+            return Err(PagingError::InvalidPermission);
+        }
+        PTE::fetch_update(slot, |mut entry| {
+            entry.set_flags(
+                entry.flags() | MappingFlags::ACCESSED | MappingFlags::DIRTY,
+                size.is_huge(),
+            );
+            entry
+        });
+        Ok((size, TlbFlushToken::page(vaddr)))
+    }
+
+    /// Clears the accessed bit of the page that maps `vaddr`.
+    ///
+    /// Returns whether the page has been accessed since the bit was last
+    /// cleared, and a token to flush the TLB entry of the page if it has, so
+    /// that the next access sets the bit again.
+    ///
+    /// Returns [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the
+    /// mapping is not present.
+    pub fn test_and_clear_accessed(
+        &mut self,
+        vaddr: VirtAddr,
+    ) -> PagingResult<(bool, TlbFlushToken)> {
+        self.test_and_clear(vaddr, MappingFlags::ACCESSED)
+    }
+
+    /// Clears the dirty bit of the page that maps `vaddr`.
+    ///
+    /// Returns whether the page has been written since the bit was last
+    /// cleared, and a token to flush the TLB entry of the page if it has, so
+    /// that the next write sets the bit again.
+    ///
+    /// Returns [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the
+    /// mapping is not present.
+    pub fn test_and_clear_dirty(&mut self, vaddr: VirtAddr) -> PagingResult<(bool, TlbFlushToken)> {
+        self.test_and_clear(vaddr, MappingFlags::DIRTY)
+    }
+
+    /// Clears the accessed bits of all the pages in a contiguous virtual
+    /// memory region.
+    ///
+    /// The region starts with `vaddr` and its size is `size`. They must be
+    /// aligned to 4K, otherwise it will return [`Err(PagingError::NotAligned)`].
+    /// Unmapped parts of the region are skipped, and huge pages that are only
+    /// partially covered by the region are split into smaller pages first.
+    ///
+    /// Returns the start addresses and sizes of the pages that have been
+    /// accessed since the bits were last cleared, and a token to flush their
+    /// TLB entries. If it fails half way, e.g. when a table cannot be
+    /// allocated to split a huge page, the returned [`RegionError`] carries
+    /// the token to flush the pages cleared so far.
+    ///
+    /// [`Err(PagingError::NotAligned)`]: PagingError::NotAligned
+    #[allow(clippy::result_large_err)] // the error carries a token like the `Ok` value
+    pub fn test_and_clear_accessed_region(
+        &mut self,
+        vaddr: VirtAddr,
+        size: usize,
+    ) -> Result<(Vec<(VirtAddr, PageSize)>, TlbFlushToken), RegionError> {
+        self.test_and_clear_region(vaddr, size, MappingFlags::ACCESSED)
+    }
+
+    /// Clears the dirty bits of all the pages in a contiguous virtual memory
+    /// region.
+    ///
+    /// It works like [`PageTable64::test_and_clear_accessed_region`], and
+    /// returns the pages that have been written since the bits were last
+    /// cleared.
+    #[allow(clippy::result_large_err)] // the error carries a token like the `Ok` value
+    pub fn test_and_clear_dirty_region(
+        &mut self,
+        vaddr: VirtAddr,
+        size: usize,
+    ) -> Result<(Vec<(VirtAddr, PageSize)>, TlbFlushToken), RegionError> {
+        self.test_and_clear_region(vaddr, size, MappingFlags::DIRTY)
+    }
+
+    /// Starts logging the writes to a contiguous virtual memory region, by
+    /// clearing the dirty bits of all the pages in it.
+    ///
+    /// The region starts with `vaddr` and its size is `size`. They must be
+    /// aligned to 4K, otherwise it will return [`Err(PagingError::NotAligned)`].
+    /// Unmapped parts of the region are skipped. When `split_huge` is true,
+    /// the huge pages in the region are split into 4K pages, so that the
+    /// writes are logged at the 4K granularity. Otherwise only the huge pages
+    /// that are partially covered by the region are split.
+    ///
+    /// The written pages can then be collected by
+    /// [`PageTable64::collect_dirty_log`].
+    ///
+    /// Returns a token to flush the TLB entries of the pages whose dirty bits
+    /// are cleared. If it fails half way, e.g. when a table cannot be
+    /// allocated to split a huge page, the returned [`RegionError`] carries
+    /// the token to flush the pages changed so far.
+    ///
+    /// If the MMU does not set the dirty bits (see [`GenericPTE::HW_DIRTY`]),
+    /// e.g. on AArch64 and LoongArch64, the writable pages are write-protected
+    /// instead, and the page fault handler must restore the write access with
+    /// [`PageTable64::resolve_dirty_fault`], which logs the write.
+    ///
+    /// [`Err(PagingError::NotAligned)`]: PagingError::NotAligned
+    #[allow(clippy::result_large_err)] // the error carries a token like the `Ok` value
+    pub fn start_dirty_log(
+        &mut self,
+        vaddr: VirtAddr,
+        size: usize,
+        split_huge: bool,
+    ) -> Result<TlbFlushToken, RegionError> {
+        if !vaddr.is_aligned(PageSize::Size4K)
+            || !memory_addr::is_aligned(size, PageSize::Size4K.into())
+        {
+            return Err(PagingError::NotAligned.into());
+        }
+        Self::check_vaddr(vaddr, size)?;
+        if self.overlaps_shared(vaddr, size) {
+            return Err(PagingError::SharedMapping.into());
+        }
+        trace!(
+            "start_dirty_log({:#x}) [{:#x}, {:#x})",
+            self.root_paddr(),
+            vaddr,
+            vaddr.as_usize().wrapping_add(size),
+        );
+        self.with_cursor(vaddr, size, |cursor, flush| {
+            while let Some(level) = cursor.find_mapped() {
+                let page_size = Self::level_page_size(level);
+                if (split_huge && level < M::LEVELS - 1) || cursor.crosses_range(page_size) {
+                    cursor.split_huge()?;
+                    continue;
+                }
+                flush.merge(cursor.test_and_clear(MappingFlags::DIRTY)?.2);
+            }
+            Ok(())
+        })
+    }
+
+    /// Collects the pages written in a contiguous virtual memory region since
+    /// the logging was started by [`PageTable64::start_dirty_log`] or the
+    /// previous collection, and clears their dirty bits.
+    ///
+    /// The region starts with `vaddr` and its size is `size`. They must be
+    /// aligned to 4K, otherwise it will return [`Err(PagingError::NotAligned)`].
+    ///
+    /// Returns a bitmap with one bit for each 4K page of the region, where bit
+    /// `i % 64` of word `i / 64` is set if the page at `vaddr + i * 4K` has
+    /// been written, and a token to flush the TLB entries of the written
+    /// pages. All the 4K pages covered by a written huge page are set. The
+    /// written pages are write-protected again if the MMU does not set the
+    /// dirty bits, like by [`PageTable64::start_dirty_log`].
+    ///
+    /// [`Err(PagingError::NotAligned)`]: PagingError::NotAligned
+    #[allow(clippy::result_large_err)] // the error carries a token like the `Ok` value
+    pub fn collect_dirty_log(
+        &mut self,
+        vaddr: VirtAddr,
+        size: usize,
+    ) -> Result<(Vec<u64>, TlbFlushToken), RegionError> {
+        let (pages, flush) = self.test_and_clear_dirty_region(vaddr, size)?;
+        let page_count = size / PageSize::Size4K as usize;
+        let mut bitmap = alloc::vec![0u64; page_count.div_ceil(64)];
+        for (start, page_size) in pages {
+            let first = (start.as_usize() - vaddr.as_usize()) / PageSize::Size4K as usize;
+            for i in first..first + page_size as usize / PageSize::Size4K as usize {
+                bitmap[i / 64] |= 1 << (i % 64);
+            }
+        }
+        Ok((bitmap, flush))
     }
 
     /// Walk the page table recursively.
@@ -240,151 +831,301 @@ impl<M: PagingMetaData, PTE: GenericPTE, IF: PagingIf<PTE>> PageTable64<M, PTE,
     /// - The index of the entry in the current-level table: `usize`
     /// - The virtual address that is mapped to the entry: [`VirtAddr`]
     /// - The reference of the entry: [`&PTE`](GenericPTE)
+    ///
+    /// Tables shared from another page table are not walked into.
     pub fn walk<F>(&self, limit: usize, func: &F) -> PagingResult
     where
         F: Fn(usize, usize, VirtAddr, &PTE),
     {
-        self.walk_recursive(
-            self.table_of(self.root_paddr()),
-            0,
-            VirtAddr::from(0),
-            limit,
-            func,
-        )
+        self.walk_recursive(self.root_paddr(), 0, VirtAddr::from(0), limit, func)
     }
 }
 
 // Private implements.
-impl<M: PagingMetaData, PTE: GenericPTE, IF: PagingIf<PTE>> PageTable64<M, PTE, IF> {
-    fn alloc_table() -> PagingResult<Box<dyn NotLeafPage<PTE>>> {
-        if let Some(page) = IF::alloc_frame() {
-            page.zero();
-            Ok(page)
-        } else {
-            Err(PagingError::NoMemory)
+impl<M: PagingMetaData, PTE: GenericPTE, IF, S: TableStore<PTE, IF>> PageTable64<M, PTE, IF, S> {
+    /// Copies the entries of the table at `paddr` of the given `level` to the
+    /// table of `child` at `child_paddr`, duplicating the next level tables
+    /// and marking writable user pages as copy-on-write.
+    fn fork_table(
+        &mut self,
+        child: &mut Self,
+        paddr: PhysAddr,
+        child_paddr: PhysAddr,
+        level: usize,
+    ) -> PagingResult {
+        for i in 0..ENTRY_COUNT {
+            let mut entry = self.entry_at(paddr, i);
+            if entry.is_unused() {
+                continue;
+            }
+            let child_slot = &child.table_of(child_paddr)[i];
+            if level == 0 && self.shared.contains(&i) {
+                PTE::store(child_slot, entry);
+            } else if level < M::LEVELS - 1 && entry.is_present() && !entry.is_huge() {
+                let next_paddr = child.alloc_table()?;
+                PTE::store(&child.table_of(child_paddr)[i], PTE::new_table(next_paddr));
+                self.fork_table(child, entry.paddr(), next_paddr, level + 1)?;
+            } else {
+                if entry
+                    .flags()
+                    .contains(MappingFlags::WRITE | MappingFlags::USER)
+                {
+                    let slot = &self.table_of(paddr)[i];
+                    PTE::fetch_update(slot, |mut entry| {
+                        entry.set_flags(
+                            (entry.flags() - MappingFlags::WRITE) | MappingFlags::COW,
+                            level < M::LEVELS - 1,
+                        );
+                        entry
+                    });
+                    entry = PTE::load(slot);
+                }
+                PTE::store(child_slot, entry);
+            }
         }
+        Ok(())
     }
 
-    fn table_of<'a>(&self, paddr: PhysAddr) -> &'a [PTE] {
-        let page = self.intrm_tables.get(&paddr).unwrap();
-        page.as_pte_slice()
+    fn test_and_clear(
+        &mut self,
+        vaddr: VirtAddr,
+        flag: MappingFlags,
+    ) -> PagingResult<(bool, TlbFlushToken)> {
+        Self::check_vaddr(vaddr, 1)?;
+        let (slot, size) = self.get_entry(vaddr)?;
+        if PTE::load(slot).is_unused() {
+            return Err(PagingError::NotMapped);
+        }
+        let old = PTE::fetch_update(slot, |mut entry| {
+            entry.set_flags(entry.flags() - flag, size.is_huge());
+            entry
+        });
+        if old.flags().contains(flag) {
+            Ok((true, TlbFlushToken::page(vaddr)))
+        } else {
+            Ok((false, TlbFlushToken::empty()))
+        }
     }
 
-    fn table_of_mut<'a>(&self, paddr: PhysAddr) -> &'a mut [PTE] {
-        let page = self.intrm_tables.get(&paddr).unwrap();
-        page.as_pte_mut_slice()
+    #[allow(clippy::result_large_err)] // the error carries a token like the `Ok` value
+    fn test_and_clear_region(
+        &mut self,
+        vaddr: VirtAddr,
+        size: usize,
+        flag: MappingFlags,
+    ) -> Result<(Vec<(VirtAddr, PageSize)>, TlbFlushToken), RegionError> {
+        if !vaddr.is_aligned(PageSize::Size4K)
+            || !memory_addr::is_aligned(size, PageSize::Size4K.into())
+        {
+            return Err(PagingError::NotAligned.into());
+        }
+        Self::check_vaddr(vaddr, size)?;
+        if self.overlaps_shared(vaddr, size) {
+            return Err(PagingError::SharedMapping.into());
+        }
+        let mut pages = Vec::new();
+        let flush = self.with_cursor(vaddr, size, |cursor, flush| {
+            while let Some(level) = cursor.find_mapped() {
+                let page_size = Self::level_page_size(level);
+                if cursor.crosses_range(page_size) {
+                    cursor.split_huge()?;
+                    continue;
+                }
+                let start = cursor.vaddr().align_down(page_size);
+                let (touched, _, page_flush) = cursor.test_and_clear(flag)?;
+                if touched {
+                    pages.push((start, page_size));
+                    flush.merge(page_flush);
+                }
+            }
+            Ok(())
+        })?;
+        Ok((pages, flush))
     }
 
-    fn next_table_mut<'a>(&self, entry: &PTE) -> PagingResult<&'a mut [PTE]> {
-        if !entry.is_present() {
-            Err(PagingError::NotMapped)
-        } else if entry.is_huge() {
-            Err(PagingError::MappedToHugePage)
+    /// Runs `f` with a cursor over the region of `size` bytes starting with
+    /// `vaddr`, which adds the TLB entries to flush to the given token, and
+    /// then finishes the cursor.
+    ///
+    /// Returns the token, which is carried by the error if `f` fails.
+    #[allow(clippy::result_large_err)] // the error carries a token like the `Ok` value
+    fn with_cursor<F>(
+        &mut self,
+        vaddr: VirtAddr,
+        size: usize,
+        f: F,
+    ) -> Result<TlbFlushToken, RegionError>
+    where
+        F: FnOnce(&mut PageTableCursor<'_, M, PTE, IF, S>, &mut TlbFlushToken) -> PagingResult,
+    {
+        if size == 0 {
+            return Ok(TlbFlushToken::empty());
+        }
+        let mut cursor = self.cursor_mut(vaddr..=vaddr + (size - 1))?;
+        let mut flush = TlbFlushToken::empty();
+        let result = f(&mut cursor, &mut flush);
+        let done = if cursor.in_range() {
+            cursor.vaddr().as_usize() - vaddr.as_usize()
         } else {
-            Ok(self.table_of_mut(entry.paddr()))
+            size
+        };
+        flush.merge(cursor.finish());
+        match result {
+            Ok(()) => Ok(flush),
+            Err(error) => Err(RegionError { error, done, flush }),
         }
     }
 
-    fn next_table_mut_or_create<'a>(&mut self, entry: &mut PTE) -> PagingResult<&'a mut [PTE]> {
-        if entry.is_unused() {
-            let page = Self::alloc_table()?;
-            let paddr = page.phys_addr();
-            *entry = GenericPTE::new_table(paddr);
-            self.intrm_tables.insert(paddr, page);
-            Ok(self.table_of_mut(paddr))
-        } else {
-            self.next_table_mut(entry)
+    /// Replaces the table pointed to by the entry that maps `vaddr` in the
+    /// table of the given `level` with a single huge page, if the entries of
+    /// the table map physically contiguous pages with identical flags.
+    ///
+    /// The table is replaced by compare-and-swap. The MMU may still set the
+    /// accessed and dirty bits of the pages in the table until the TLB is
+    /// flushed, so the huge page is marked accessed, and dirty if it is
+    /// writable.
+    ///
+    /// Returns whether the table is collapsed.
+    fn try_collapse(&mut self, vaddr: VirtAddr, level: usize) -> bool {
+        if level == 0 && self.is_exported(vaddr) {
+            return false;
+        }
+        let Ok(parent_paddr) = self.table_paddr_at(vaddr, level) else {
+            return false;
+        };
+        let index = Self::entry_index(vaddr, level);
+        let slot = &self.table_of(parent_paddr)[index];
+        let mut entry = PTE::load(slot);
+        if !entry.is_present() || entry.is_huge() {
+            return false;
+        }
+        let page_size = Self::level_page_size(level);
+        let sub_size = Self::level_page_size(level + 1);
+        let table_paddr = entry.paddr();
+        let table = self.table_of(table_paddr);
+        // the accessed and dirty bits of the pages are merged
+        let bits = MappingFlags::ACCESSED | MappingFlags::DIRTY;
+        let first = PTE::load(&table[0]);
+        let (paddr, flags) = (first.paddr(), first.flags() - bits);
+        if !paddr.is_aligned(page_size) {
+            return false;
+        }
+        let contiguous = table.iter().enumerate().all(|(i, sub_slot)| {
+            let sub_entry = PTE::load(sub_slot);
+            ((level + 1 == M::LEVELS - 1 && !sub_entry.is_unused()) || sub_entry.is_huge())
+                && sub_entry.paddr() == paddr + i * sub_size as usize
+                && sub_entry.flags() - bits == flags
+        });
+        if !contiguous {
+            return false;
+        }
+        let mut flags = table
+            .iter()
+            .fold(flags | MappingFlags::ACCESSED, |flags, sub_slot| {
+                flags | (PTE::load(sub_slot).flags() & bits)
+            });
+        if flags.contains(MappingFlags::WRITE) {
+            flags |= MappingFlags::DIRTY;
+        }
+        let huge = PTE::new_page(paddr, flags, true);
+        loop {
+            match PTE::compare_exchange(slot, entry, huge) {
+                Ok(_) => break,
+                // e.g. the accessed bit of the table entry is set by the MMU
+                Err(actual) if actual.is_present() && !actual.is_huge() => {
+                    debug_assert_eq!(actual.paddr(), table_paddr);
+                    entry = actual;
+                }
+                Err(_) => return false,
+            }
         }
+        self.free_table(table_paddr);
+        true
     }
 
-    fn get_entry_mut(&self, vaddr: VirtAddr) -> PagingResult<(&mut PTE, PageSize)> {
-        let p3 = if M::LEVELS == 3 {
-            self.table_of_mut(self.root_paddr())
-        } else if M::LEVELS == 4 {
-            let p4 = self.table_of_mut(self.root_paddr());
-            let p4e = &mut p4[p4_index(vaddr)];
-            self.next_table_mut(p4e)?
-        } else {
-            unreachable!()
-        };
-        let p3e = &mut p3[p3_index(vaddr)];
-        if p3e.is_huge() {
-            return Ok((p3e, PageSize::Size1G));
+    /// Allocates the tables pointed to by the root table entries at `indices`
+    /// that are unused, and keeps them from being reclaimed, so that they can
+    /// be shared with other page tables.
+    fn export_root_entries(&mut self, indices: Range<usize>) -> PagingResult {
+        let root = self.root_paddr();
+        for i in indices.clone() {
+            match self.next_table_or_create(root, i) {
+                Ok(_) | Err(PagingError::MappedToHugePage) => {}
+                Err(e) => return Err(e),
+            }
         }
+        for i in indices {
+            self.exported[i / 64] |= 1 << (i % 64);
+        }
+        Ok(())
+    }
 
-        let p2 = self.next_table_mut(p3e)?;
-        let p2e = &mut p2[p2_index(vaddr)];
-        if p2e.is_huge() {
-            return Ok((p2e, PageSize::Size2M));
+    /// Returns whether the region of `size` bytes starting with `vaddr`
+    /// overlaps with the mappings shared from another page table.
+    fn overlaps_shared(&self, vaddr: VirtAddr, size: usize) -> bool {
+        if self.shared.is_empty() || size == 0 {
+            return false;
         }
+        let shift = Self::level_shift(0);
+        let first = vaddr.as_usize() >> shift;
+        let last = (vaddr.as_usize() + (size - 1)) >> shift;
+        (first..=last).any(|i| self.shared.contains(&(i % ENTRY_COUNT)))
+    }
+}
 
-        let p1 = self.next_table_mut(p2e)?;
-        let p1e = &mut p1[p1_index(vaddr)];
-        Ok((p1e, PageSize::Size4K))
+impl<M: PagingMetaData, PTE: GenericPTE, IF, S: TableStore<PTE, IF>> PageTableWalk
+    for PageTable64<M, PTE, IF, S>
+{
+    type Meta = M;
+    type Entry = PTE;
+
+    const INDEX_BITS: usize = 9;
+
+    fn root_table(&self) -> PhysAddr {
+        self.root_paddr
     }
 
-    fn get_entry_mut_or_create(
-        &mut self,
-        vaddr: VirtAddr,
-        page_size: PageSize,
-    ) -> PagingResult<&mut PTE> {
-        let p3 = if M::LEVELS == 3 {
-            self.table_of_mut(self.root_paddr())
-        } else if M::LEVELS == 4 {
-            let p4 = self.table_of_mut(self.root_paddr());
-            let p4e = &mut p4[p4_index(vaddr)];
-            self.next_table_mut_or_create(p4e)?
+    fn table_of(&self, paddr: PhysAddr) -> &[PTE::Atomic] {
+        self.tables.table(&self.alloc, paddr)
+    }
+
+    fn alloc_table(&mut self) -> PagingResult<PhysAddr> {
+        self.tables.alloc_table(&mut self.alloc)
+    }
+
+    fn dealloc_table(&mut self, paddr: PhysAddr) {
+        self.tables.dealloc_table(&mut self.alloc, paddr);
+    }
+
+    /// Releases the intermediate table at `paddr` that has been unlinked, or
+    /// keeps it until the TLB is flushed.
+    fn free_table(&mut self, paddr: PhysAddr) {
+        if self.defer_reclaim {
+            self.reclaimed_tables.push(paddr);
         } else {
-            unreachable!()
-        };
-        let p3e = &mut p3[p3_index(vaddr)];
-        if page_size == PageSize::Size1G {
-            return Ok(p3e);
+            self.dealloc_table(paddr);
         }
+    }
 
-        let p2 = self.next_table_mut_or_create(p3e)?;
-        let p2e = &mut p2[p2_index(vaddr)];
-        if page_size == PageSize::Size2M {
-            return Ok(p2e);
-        }
+    fn track_access(&self) -> bool {
+        self.track_access
+    }
 
-        let p1 = self.next_table_mut_or_create(p2e)?;
-        let p1e = &mut p1[p1_index(vaddr)];
-        Ok(p1e)
+    fn is_shared(&self, vaddr: VirtAddr) -> bool {
+        self.shared.contains(&Self::entry_index(vaddr, 0))
     }
 
-    fn walk_recursive<F>(
-        &self,
-        table: &[PTE],
-        level: usize,
-        start_vaddr: VirtAddr,
-        limit: usize,
-        func: &F,
-    ) -> PagingResult
-    where
-        F: Fn(usize, usize, VirtAddr, &PTE),
-    {
-        let mut n = 0;
-        for (i, entry) in table.iter().enumerate() {
-            let vaddr = start_vaddr + (i << (12 + (M::LEVELS - 1 - level) * 9));
-            if entry.is_present() {
-                func(level, i, vaddr, entry);
-                if level < M::LEVELS - 1 && !entry.is_huge() {
-                    let table_entry = self.next_table_mut(entry)?;
-                    self.walk_recursive(table_entry, level + 1, vaddr, limit, func)?;
-                }
-                n += 1;
-                if n >= limit {
-                    break;
-                }
-            }
-        }
-        Ok(())
+    fn is_exported(&self, vaddr: VirtAddr) -> bool {
+        let index = Self::entry_index(vaddr, 0);
+        self.exported[index / 64] & (1 << (index % 64)) != 0
     }
 }
 
-impl<M: PagingMetaData, PTE: GenericPTE, IF: PagingIf<PTE>> Drop for PageTable64<M, PTE, IF> {
+impl<M: PagingMetaData, PTE: GenericPTE, IF, S: TableStore<PTE, IF>> Drop
+    for PageTable64<M, PTE, IF, S>
+{
     fn drop(&mut self) {
-        self.intrm_tables.clear();
+        self.release_reclaimed_tables();
+        self.free_tables_recursive(self.root_paddr(), 0, VirtAddr::from(0));
     }
 }
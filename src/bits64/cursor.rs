@@ -0,0 +1,471 @@
+use alloc::vec::Vec;
+use core::ops::RangeInclusive;
+
+use memory_addr::{PhysAddr, VirtAddr};
+
+use super::PageTable64;
+use crate::{
+    walk::{leaf_flags, PageTableWalk},
+    GenericPTE, MappingFlags, PageSize, PagingError, PagingMetaData, PagingResult, TableStore,
+    TlbFlushToken,
+};
+
+/// A cursor that moves monotonically through a virtual address range of a
+/// [`PageTable64`], created by [`PageTable64::cursor_mut`].
+///
+/// It caches the tables on the path to its current position, so consecutive
+/// operations only walk the levels that change instead of starting from the
+/// root every time.
+///
+/// Intermediate tables that become empty after unmapping are reclaimed when the
+/// cursor leaves them, or is finished by [`PageTableCursor::finish`] or
+/// dropped. [`PageTableCursor::finish`] returns a token to flush the entire
+/// TLB if any table is reclaimed, as the hardware may still cache it. When the
+/// cursor is dropped instead, the entire TLB must be flushed if it has
+/// unmapped any page.
+pub struct PageTableCursor<'a, M: PagingMetaData, PTE: GenericPTE, IF, S: TableStore<PTE, IF>> {
+    pt: &'a mut PageTable64<M, PTE, IF, S>,
+    vaddr: VirtAddr,
+    /// The start of the range.
+    start: VirtAddr,
+    /// The last address of the range, which may be the top of the address
+    /// space.
+    last: VirtAddr,
+    /// Whether the cursor has moved past the last address.
+    past_end: bool,
+    /// Physical addresses of the tables on the path to `vaddr`, starting with
+    /// the root table. `path[level]` is the table of `level`.
+    path: Vec<PhysAddr>,
+    /// An address in the table where entries have been unmapped, and the level
+    /// of that table.
+    unmapped_in: Option<(VirtAddr, usize)>,
+    /// Whether any table has been reclaimed.
+    reclaimed: bool,
+}
+
+impl<M: PagingMetaData, PTE: GenericPTE, IF, S: TableStore<PTE, IF>> PageTable64<M, PTE, IF, S> {
+    /// Creates a cursor that starts at the start of `range` and can operate on
+    /// the pages in `range`, which includes its last address, so that it can
+    /// reach the top of the address space.
+    ///
+    /// Returns [`Err(PagingError::InvalidAddress)`] if `range` is empty or
+    /// contains invalid addresses, or
+    /// [`Err(PagingError::SharedMapping)`](PagingError::SharedMapping) if
+    /// `range` overlaps with the mappings shared from another page table.
+    ///
+    /// [`Err(PagingError::InvalidAddress)`]: PagingError::InvalidAddress
+    pub fn cursor_mut(
+        &mut self,
+        range: RangeInclusive<VirtAddr>,
+    ) -> PagingResult<PageTableCursor<'_, M, PTE, IF, S>> {
+        let (start, last) = (*range.start(), *range.end());
+        if range.is_empty() {
+            return Err(PagingError::InvalidAddress(last.as_usize()));
+        }
+        Self::check_vaddr_range(start, last)?;
+        if self.overlaps_shared(start, last.as_usize() - start.as_usize() + 1) {
+            return Err(PagingError::SharedMapping);
+        }
+        let mut path = Vec::with_capacity(M::LEVELS);
+        path.push(self.root_paddr());
+        Ok(PageTableCursor {
+            pt: self,
+            vaddr: start,
+            start,
+            last,
+            past_end: false,
+            path,
+            unmapped_in: None,
+            reclaimed: false,
+        })
+    }
+}
+
+impl<M: PagingMetaData, PTE: GenericPTE, IF, S: TableStore<PTE, IF>>
+    PageTableCursor<'_, M, PTE, IF, S>
+{
+    /// Returns the current position of the cursor.
+    ///
+    /// After the cursor has moved past the top of the address space, it stays
+    /// at the last page it has moved past.
+    pub const fn vaddr(&self) -> VirtAddr {
+        self.vaddr
+    }
+
+    /// Returns whether the current position is still in the range.
+    pub const fn in_range(&self) -> bool {
+        !self.past_end && self.vaddr.as_usize() <= self.last.as_usize()
+    }
+
+    /// Finishes the operations of the cursor, and reclaims the table it
+    /// unmapped pages from last if it is empty.
+    ///
+    /// Returns a token to flush the entire TLB if any table has been reclaimed
+    /// by the cursor, or an empty token otherwise.
+    pub fn finish(mut self) -> TlbFlushToken {
+        self.reclaim();
+        if self.reclaimed {
+            TlbFlushToken::all()
+        } else {
+            TlbFlushToken::empty()
+        }
+    }
+
+    /// Moves the cursor forward to `vaddr`.
+    ///
+    /// Returns [`Err(PagingError::InvalidAddress)`](PagingError::InvalidAddress)
+    /// if `vaddr` is before the current position, as the cursor can only move
+    /// forward, or out of the range.
+    pub fn seek(&mut self, vaddr: VirtAddr) -> PagingResult {
+        if !self.in_range() || vaddr < self.vaddr || vaddr > self.last {
+            return Err(PagingError::InvalidAddress(vaddr.as_usize()));
+        }
+        self.move_to(vaddr);
+        Ok(())
+    }
+
+    /// Queries the page at the current position.
+    ///
+    /// Returns the physical address that the current position maps to, the
+    /// mapping flags, and the page size.
+    ///
+    /// Returns [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the
+    /// mapping is not present.
+    pub fn query(&mut self) -> PagingResult<(PhysAddr, MappingFlags, PageSize)> {
+        let level = self.leaf_level()?;
+        let size = PageTable64::<M, PTE, IF, S>::level_page_size(level);
+        let entry = self.entry(level);
+        Ok((
+            entry.paddr() + self.vaddr.align_offset(size),
+            entry.flags(),
+            size,
+        ))
+    }
+
+    /// Maps a page of `page_size` at the current position to the physical
+    /// frame starting with `target`, and moves the cursor past it.
+    ///
+    /// Returns a token to flush the TLB entry of the page.
+    ///
+    /// Returns [`Err(PagingError::UnsupportedPageSize)`] if pages of
+    /// `page_size` cannot be mapped,
+    /// [`Err(PagingError::NotAligned)`](PagingError::NotAligned) if
+    /// the current position is not aligned to `page_size`, or
+    /// [`Err(PagingError::AlreadyMapped)`](PagingError::AlreadyMapped) if the
+    /// mapping is already present, or [`Err(PagingError::InvalidAddress)`] if
+    /// the page is not entirely in the range or the frame is out of the valid
+    /// physical addresses.
+    ///
+    /// It is not named `map` to avoid being shadowed by [`Iterator::map`].
+    ///
+    /// [`Err(PagingError::UnsupportedPageSize)`]: PagingError::UnsupportedPageSize
+    /// [`Err(PagingError::InvalidAddress)`]: PagingError::InvalidAddress
+    pub fn map_page(
+        &mut self,
+        target: PhysAddr,
+        page_size: PageSize,
+        flags: MappingFlags,
+    ) -> PagingResult<TlbFlushToken> {
+        let level = PageTable64::<M, PTE, IF, S>::page_size_level(page_size)?;
+        if !self.vaddr.is_aligned(page_size) {
+            return Err(PagingError::NotAligned);
+        }
+        self.check_in_range(self.vaddr, page_size as usize)?;
+        PageTable64::<M, PTE, IF, S>::check_paddr(
+            target.align_down(page_size),
+            page_size as usize,
+        )?;
+        PageTable64::<M, PTE, IF, S>::check_flags(flags)?;
+        let flags = leaf_flags(self.pt.track_access, flags, MappingFlags::empty());
+        let slot = self.slot_or_create(level)?;
+        if !PTE::load(slot).is_unused() {
+            return Err(PagingError::AlreadyMapped);
+        }
+        let entry = PTE::new_page(target.align_down(page_size), flags, page_size.is_huge());
+        PTE::store(slot, entry);
+        let vaddr = self.vaddr;
+        self.advance(vaddr, page_size as usize);
+        Ok(TlbFlushToken::page(vaddr))
+    }
+
+    /// Unmaps the page at the current position, and moves the cursor past it.
+    ///
+    /// Returns the value of the entry right before it is cleared, the page
+    /// size, and a token to flush the TLB entry of the page.
+    ///
+    /// Returns [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the
+    /// mapping is not present, or [`Err(PagingError::InvalidAddress)`] if the
+    /// page is not entirely in the range, e.g. a huge page that crosses its
+    /// boundary.
+    ///
+    /// [`Err(PagingError::InvalidAddress)`]: PagingError::InvalidAddress
+    pub fn unmap(&mut self) -> PagingResult<(PTE, PageSize, TlbFlushToken)> {
+        let vaddr = self.vaddr;
+        let (level, size) = self.leaf_in_range()?;
+        let old = PTE::fetch_update(self.slot(level), |mut entry| {
+            entry.clear();
+            entry
+        });
+        if level > 0 {
+            self.unmapped_in = Some((vaddr, level));
+        }
+        self.advance(vaddr.align_down(size), size as usize);
+        Ok((old, size, TlbFlushToken::page(vaddr)))
+    }
+
+    /// Changes the mapping flags of the page at the current position, and
+    /// moves the cursor past it.
+    ///
+    /// Returns the page size of the mapping and a token to flush the TLB entry
+    /// of the page.
+    ///
+    /// Returns [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the
+    /// mapping is not present, or [`Err(PagingError::InvalidAddress)`] if the
+    /// page is not entirely in the range, like [`PageTableCursor::unmap`].
+    ///
+    /// [`Err(PagingError::InvalidAddress)`]: PagingError::InvalidAddress
+    pub fn protect(&mut self, flags: MappingFlags) -> PagingResult<(PageSize, TlbFlushToken)> {
+        PageTable64::<M, PTE, IF, S>::check_flags(flags)?;
+        let vaddr = self.vaddr;
+        let (level, size) = self.leaf_in_range()?;
+        let track_access = self.pt.track_access;
+        PTE::fetch_update(self.slot(level), |mut entry| {
+            let flags = leaf_flags(track_access, flags, entry.flags());
+            entry.set_flags(flags, size.is_huge());
+            entry
+        });
+        self.advance(vaddr.align_down(size), size as usize);
+        Ok((size, TlbFlushToken::page(vaddr)))
+    }
+
+    /// Clears `flags` (e.g. [`MappingFlags::ACCESSED`] or
+    /// [`MappingFlags::DIRTY`]) of the page at the current position, and moves
+    /// the cursor past it.
+    ///
+    /// Returns whether any of `flags` was set, the page size, and a token to
+    /// flush the TLB entry of the page if any of them was set.
+    ///
+    /// Returns [`Err(PagingError::NotMapped)`](PagingError::NotMapped) if the
+    /// mapping is not present, or [`Err(PagingError::InvalidAddress)`] if the
+    /// page is not entirely in the range, like [`PageTableCursor::unmap`].
+    ///
+    /// [`Err(PagingError::InvalidAddress)`]: PagingError::InvalidAddress
+    pub fn test_and_clear(
+        &mut self,
+        flags: MappingFlags,
+    ) -> PagingResult<(bool, PageSize, TlbFlushToken)> {
+        let vaddr = self.vaddr;
+        let (level, size) = self.leaf_in_range()?;
+        let old = PTE::fetch_update(self.slot(level), |mut entry| {
+            entry.set_flags(entry.flags() - flags, size.is_huge());
+            entry
+        });
+        self.advance(vaddr.align_down(size), size as usize);
+        if old.flags().intersects(flags) {
+            Ok((true, size, TlbFlushToken::page(vaddr)))
+        } else {
+            Ok((false, size, TlbFlushToken::empty()))
+        }
+    }
+
+    /// Moves the cursor to the first mapped page at or after the current
+    /// position within the range, without passing it.
+    ///
+    /// Returns the level of the leaf entry that maps the page, or `None` if
+    /// there are no more mapped pages in the range.
+    pub(super) fn find_mapped(&mut self) -> Option<usize> {
+        while self.in_range() {
+            let vaddr = self.vaddr;
+            let level = self.walk_down();
+            let entry = self.entry(level);
+            if (level == M::LEVELS - 1 && !entry.is_unused()) || entry.is_huge() {
+                return Some(level);
+            }
+            // skip the unmapped region covered by the entry
+            let size = 1usize << PageTable64::<M, PTE, IF, S>::level_shift(level);
+            self.advance(vaddr.align_down(size), size);
+        }
+        None
+    }
+
+    /// Returns whether the page of `page_size` at the current position is not
+    /// entirely in the range, so it has to be split before it is changed.
+    pub(super) fn crosses_range(&self, page_size: PageSize) -> bool {
+        let start = self.vaddr.align_down(page_size);
+        self.check_in_range(start, page_size as usize).is_err()
+    }
+
+    /// Splits the huge page at the current position into pages of the next
+    /// smaller size.
+    ///
+    /// Huge pages mapped by the root table entries shared with other page
+    /// tables are not split, as the other page tables would still map them,
+    /// and [`Err(PagingError::SharedMapping)`](PagingError::SharedMapping) is
+    /// returned.
+    pub(super) fn split_huge(&mut self) -> PagingResult {
+        let level = self.path.len() - 1;
+        if level == 0 && self.pt.is_exported(self.vaddr) {
+            return Err(PagingError::SharedMapping);
+        }
+        let index = PageTable64::<M, PTE, IF, S>::entry_index(self.vaddr, level);
+        let paddr = self.pt.split_huge(self.path[level], index, level)?;
+        self.path.push(paddr);
+        Ok(())
+    }
+}
+
+impl<M: PagingMetaData, PTE: GenericPTE, IF, S: TableStore<PTE, IF>> Drop
+    for PageTableCursor<'_, M, PTE, IF, S>
+{
+    fn drop(&mut self) {
+        self.reclaim();
+    }
+}
+
+/// Iterates over the mapped pages from the current position to the end of
+/// the range.
+///
+/// Each item is the virtual address where the page starts, the physical
+/// address of the target frame, the mapping flags, and the page size.
+impl<M: PagingMetaData, PTE: GenericPTE, IF, S: TableStore<PTE, IF>> Iterator
+    for PageTableCursor<'_, M, PTE, IF, S>
+{
+    type Item = (VirtAddr, PhysAddr, MappingFlags, PageSize);
+
+    /// Finds the first mapped page at or after the current position within
+    /// the range, and moves the cursor past it.
+    fn next(&mut self) -> Option<Self::Item> {
+        let level = self.find_mapped()?;
+        let size = PageTable64::<M, PTE, IF, S>::level_page_size(level);
+        let start = self.vaddr.align_down(size);
+        let entry = self.entry(level);
+        let ret = (start, entry.paddr(), entry.flags(), size);
+        self.advance(start, size as usize);
+        Some(ret)
+    }
+}
+
+// Private implements.
+impl<M: PagingMetaData, PTE: GenericPTE, IF, S: TableStore<PTE, IF>>
+    PageTableCursor<'_, M, PTE, IF, S>
+{
+    /// Checks that the current position and the `size` bytes starting with
+    /// `start` are in the range.
+    fn check_in_range(&self, start: VirtAddr, size: usize) -> PagingResult {
+        let (start, last) = (start.as_usize(), self.last.as_usize());
+        if !self.in_range() || start < self.start.as_usize() || start > last {
+            Err(PagingError::InvalidAddress(start))
+        } else if last - start < size - 1 {
+            Err(PagingError::InvalidAddress(start + (size - 1)))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the level of the leaf entry that maps the current position and
+    /// the size of its page, which must be entirely in the range.
+    fn leaf_in_range(&mut self) -> PagingResult<(usize, PageSize)> {
+        self.check_in_range(self.vaddr, 1)?;
+        let level = self.leaf_level()?;
+        let size = PageTable64::<M, PTE, IF, S>::level_page_size(level);
+        self.check_in_range(self.vaddr.align_down(size), size as usize)?;
+        Ok((level, size))
+    }
+
+    /// Moves the cursor past the `size` bytes starting with `start`, or past
+    /// the end of the range if they reach the top of the address space.
+    fn advance(&mut self, start: VirtAddr, size: usize) {
+        match start.as_usize().checked_add(size) {
+            Some(next) => self.move_to(VirtAddr::from(next)),
+            None => {
+                self.reclaim();
+                self.past_end = true;
+            }
+        }
+    }
+
+    /// Moves the cursor to `vaddr`, keeping the cached tables that also cover
+    /// it, and reclaiming the table the cursor leaves if it becomes empty.
+    fn move_to(&mut self, vaddr: VirtAddr) {
+        let old = self.vaddr;
+        self.vaddr = vaddr;
+        let leaves = |level: usize| {
+            // Whether the table of `level` covering `old` does not cover `vaddr`.
+            let shift = PageTable64::<M, PTE, IF, S>::level_shift(level - 1);
+            (old.as_usize() ^ vaddr.as_usize()) >> shift != 0
+        };
+        let keep = (1..self.path.len()).find(|&level| leaves(level));
+        if let Some(keep) = keep {
+            self.path.truncate(keep);
+        }
+        if let Some((unmapped, level)) = self.unmapped_in {
+            if (unmapped.as_usize() ^ vaddr.as_usize())
+                >> PageTable64::<M, PTE, IF, S>::level_shift(level - 1)
+                != 0
+            {
+                self.reclaim();
+            }
+        }
+    }
+
+    fn reclaim(&mut self) {
+        if let Some((vaddr, level)) = self.unmapped_in.take() {
+            self.reclaimed |= self.pt.reclaim_tables(vaddr, level);
+            // The reclaimed tables may be on the cached path.
+            self.path.truncate(1);
+        }
+    }
+
+    /// Returns the slot of the entry that maps the current position in the
+    /// cached table of `level`.
+    fn slot(&self, level: usize) -> &PTE::Atomic {
+        let index = PageTable64::<M, PTE, IF, S>::entry_index(self.vaddr, level);
+        &self.pt.table_of(self.path[level])[index]
+    }
+
+    /// Loads the entry that maps the current position in the cached table of
+    /// `level`.
+    fn entry(&self, level: usize) -> PTE {
+        PTE::load(self.slot(level))
+    }
+
+    /// Walks down from the deepest cached table to the entry that maps the
+    /// current position, stopping at leaf entries and at entries that are not
+    /// present.
+    ///
+    /// Returns the level of the entry.
+    fn walk_down(&mut self) -> usize {
+        loop {
+            let level = self.path.len() - 1;
+            let entry = self.entry(level);
+            if level == M::LEVELS - 1 || !entry.is_present() || entry.is_huge() {
+                return level;
+            }
+            let paddr = entry.paddr();
+            self.path.push(paddr);
+        }
+    }
+
+    /// Returns the level of the leaf entry that maps the current position.
+    fn leaf_level(&mut self) -> PagingResult<usize> {
+        let level = self.walk_down();
+        let entry = self.entry(level);
+        if (level < M::LEVELS - 1 && !entry.is_huge()) || entry.is_unused() {
+            return Err(PagingError::NotMapped);
+        }
+        Ok(level)
+    }
+
+    /// Returns the slot of the entry that maps the current position in the
+    /// table of `target_level`, creating intermediate tables if necessary.
+    fn slot_or_create(&mut self, target_level: usize) -> PagingResult<&PTE::Atomic> {
+        self.path.truncate(target_level + 1);
+        while self.path.len() <= target_level {
+            let level = self.path.len() - 1;
+            let index = PageTable64::<M, PTE, IF, S>::entry_index(self.vaddr, level);
+            let paddr = self.pt.next_table_or_create(self.path[level], index)?;
+            self.path.push(paddr);
+        }
+        Ok(self.slot(target_level))
+    }
+}
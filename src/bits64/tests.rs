@@ -0,0 +1,1006 @@
+use alloc::sync::Arc;
+
+use super::PageTable64;
+use crate::mock::{pa, va, LinearMockIf, MockIf, Stats};
+use crate::walk::PageTableWalk;
+use crate::{
+    A64PagingMetaData, GenericPTE, LA64MetaData, LinearTables, MapRegionError, MappingFlags,
+    PageSize, PagingError, PagingMetaData, Rv64PTE, Sv39MetaData, Sv48MetaData, Sv57MetaData,
+    X64Level5MetaData, X64PagingMetaData, A64PTE, LA64PTE, X64PTE,
+};
+
+const RW: MappingFlags = MappingFlags::READ.union(MappingFlags::WRITE);
+const AD: MappingFlags = MappingFlags::ACCESSED.union(MappingFlags::DIRTY);
+
+type MockPageTable<M = Sv39MetaData, PTE = Rv64PTE> = PageTable64<M, PTE, MockIf<PTE>>;
+
+fn new_page_table<M: PagingMetaData, PTE: GenericPTE + 'static>(
+) -> (MockPageTable<M, PTE>, Arc<Stats>)
+where
+    PTE::Atomic: Default,
+{
+    let stats = Stats::new();
+    let pt = MockPageTable::try_new_in(MockIf::new(stats.clone())).unwrap();
+    (pt, stats)
+}
+
+#[test]
+fn unmap_part_of_huge_pages() {
+    let (mut pt, stats) = new_page_table::<Sv39MetaData, Rv64PTE>();
+    pt.map(va(0x20_0000), pa(0x4000_0000), PageSize::Size2M, RW)
+        .unwrap()
+        .ignore();
+    pt.map(va(0x4000_0000), pa(0x8000_0000), PageSize::Size1G, RW)
+        .unwrap()
+        .ignore();
+    let flags = RW | AD;
+    assert_eq!(stats.live(), 2);
+
+    pt.unmap_region(va(0x20_1000), 0x1000).unwrap().ignore();
+    assert_eq!(
+        pt.query(va(0x20_0000)).unwrap(),
+        (pa(0x4000_0000), flags, PageSize::Size4K)
+    );
+    assert!(matches!(
+        pt.query(va(0x20_1000)),
+        Err(PagingError::NotMapped)
+    ));
+    assert_eq!(
+        pt.query(va(0x3f_f000)).unwrap(),
+        (pa(0x401f_f000), flags, PageSize::Size4K)
+    );
+    assert_eq!(stats.live(), 3);
+
+    pt.unmap_region(va(0x4000_0000), 0x20_0000)
+        .unwrap()
+        .ignore();
+    pt.unmap_region(va(0x4020_0000), 0x1000).unwrap().ignore();
+    assert!(matches!(
+        pt.query(va(0x4000_0000)),
+        Err(PagingError::NotMapped)
+    ));
+    assert!(matches!(
+        pt.query(va(0x4020_0000)),
+        Err(PagingError::NotMapped)
+    ));
+    assert_eq!(
+        pt.query(va(0x4020_1000)).unwrap(),
+        (pa(0x8020_1000), flags, PageSize::Size4K)
+    );
+    assert_eq!(
+        pt.query(va(0x4040_0000)).unwrap(),
+        (pa(0x8040_0000), flags, PageSize::Size2M)
+    );
+    assert_eq!(stats.live(), 5);
+}
+
+#[test]
+fn reclaim_frees_tables() {
+    let (mut pt, stats) = new_page_table::<Sv39MetaData, Rv64PTE>();
+    pt.map_region(va(0x1000), pa(0x1000), 0x2000, RW, false)
+        .unwrap()
+        .ignore();
+    assert_eq!(stats.live(), 3);
+    assert!(!pt.unmap_region(va(0x1000), 0x1000).unwrap().is_all());
+    assert_eq!(stats.live(), 3);
+    assert!(pt.unmap_region(va(0x2000), 0x1000).unwrap().is_all());
+    assert_eq!(stats.live(), 1);
+
+    pt.set_deferred_reclaim(true);
+    pt.map(va(0x1000), pa(0x1000), PageSize::Size4K, RW)
+        .unwrap()
+        .ignore();
+    assert!(pt.unmap(va(0x1000)).unwrap().2.is_all());
+    assert_eq!(stats.live(), 3);
+    pt.release_reclaimed_tables();
+    assert_eq!(stats.live(), 1);
+
+    drop(pt);
+    assert_eq!(stats.live(), 0);
+}
+
+#[test]
+fn cursor_caches_path() {
+    let (mut pt, stats) = new_page_table::<Sv39MetaData, Rv64PTE>();
+    pt.map(va(0x1000), pa(0x1000), PageSize::Size4K, RW)
+        .unwrap()
+        .ignore();
+
+    // walking from the root accesses every level for each page
+    let before = stats.lookups();
+    for i in 2..6 {
+        pt.map(va(i << 12), pa(i << 12), PageSize::Size4K, RW)
+            .unwrap()
+            .ignore();
+    }
+    let walks = stats.lookups() - before;
+
+    let mut cursor = pt.cursor_mut(va(0x6000)..=va(0x20_0fff)).unwrap();
+    cursor
+        .map_page(pa(0x6000), PageSize::Size4K, RW)
+        .unwrap()
+        .ignore();
+    let before = stats.lookups();
+    for i in 7..11 {
+        cursor
+            .map_page(pa(i << 12), PageSize::Size4K, RW)
+            .unwrap()
+            .ignore();
+    }
+    assert!(stats.lookups() - before < walks);
+
+    // leaving the last-level table drops it from the path
+    cursor.seek(va(0x20_0000)).unwrap();
+    cursor
+        .map_page(pa(0x20_0000), PageSize::Size4K, RW)
+        .unwrap()
+        .ignore();
+    assert!(cursor.finish().is_empty());
+    assert_eq!(stats.live(), 4);
+
+    let pages: alloc::vec::Vec<_> = pt
+        .cursor_mut(va(0)..=va(0x3f_ffff))
+        .unwrap()
+        .map(|(vaddr, paddr, _, size)| (vaddr, paddr, size))
+        .collect();
+    assert_eq!(pages.len(), 11);
+    assert_eq!(pages[10], (va(0x20_0000), pa(0x20_0000), PageSize::Size4K));
+
+    let mut cursor = pt.cursor_mut(va(0x1000)..=va(0x20_0fff)).unwrap();
+    while cursor.vaddr() < va(0xb000) {
+        cursor.unmap().unwrap().2.ignore();
+    }
+    cursor.seek(va(0x20_0000)).unwrap();
+    cursor.unmap().unwrap().2.ignore();
+    assert!(cursor.finish().is_all());
+    assert_eq!(stats.live(), 1);
+}
+
+#[test]
+fn protect_part_of_huge_pages() {
+    let (mut pt, _) = new_page_table::<Sv39MetaData, Rv64PTE>();
+    pt.map(va(0x20_0000), pa(0x4000_0000), PageSize::Size2M, RW)
+        .unwrap()
+        .ignore();
+    pt.map(va(0x4000_0000), pa(0x8000_0000), PageSize::Size1G, RW)
+        .unwrap()
+        .ignore();
+    let (rw, r) = (RW | AD, MappingFlags::READ | AD);
+
+    pt.protect_region(va(0x20_2000), 0x1000, MappingFlags::READ)
+        .unwrap()
+        .ignore();
+    assert_eq!(
+        pt.query(va(0x20_1000)).unwrap(),
+        (pa(0x4000_1000), rw, PageSize::Size4K)
+    );
+    assert_eq!(
+        pt.query(va(0x20_2000)).unwrap(),
+        (pa(0x4000_2000), r, PageSize::Size4K)
+    );
+
+    pt.protect_region(va(0x4020_0000), 0x20_1000, MappingFlags::READ)
+        .unwrap()
+        .ignore();
+    assert_eq!(
+        pt.query(va(0x4000_0000)).unwrap(),
+        (pa(0x8000_0000), rw, PageSize::Size2M)
+    );
+    assert_eq!(
+        pt.query(va(0x4020_0000)).unwrap(),
+        (pa(0x8020_0000), r, PageSize::Size2M)
+    );
+    assert_eq!(
+        pt.query(va(0x4040_0000)).unwrap(),
+        (pa(0x8040_0000), r, PageSize::Size4K)
+    );
+    assert_eq!(
+        pt.query(va(0x4040_1000)).unwrap(),
+        (pa(0x8040_1000), rw, PageSize::Size4K)
+    );
+    assert_eq!(
+        pt.query(va(0x7fe0_0000)).unwrap(),
+        (pa(0xbfe0_0000), rw, PageSize::Size2M)
+    );
+}
+
+#[test]
+fn collapse_into_huge_pages() {
+    let (mut pt, stats) = new_page_table::<Sv39MetaData, Rv64PTE>();
+    pt.map_region(va(0x4000_0000), pa(0x8000_0000), 0x20_0000, RW, false)
+        .unwrap()
+        .ignore();
+    pt.map_region(va(0x4020_0000), pa(0x8020_0000), 0x3fe0_0000, RW, true)
+        .unwrap()
+        .ignore();
+    assert_eq!(stats.live(), 3);
+    // a differing page keeps its table
+    pt.protect_region(va(0x4000_1000), 0x1000, MappingFlags::READ)
+        .unwrap()
+        .ignore();
+    assert!(pt
+        .collapse_region(va(0x4000_0000), 0x4000_0000)
+        .unwrap()
+        .is_empty());
+    assert_eq!(stats.live(), 3);
+
+    pt.protect_region(va(0x4000_1000), 0x1000, RW)
+        .unwrap()
+        .ignore();
+    assert!(pt
+        .collapse_region(va(0x4000_0000), 0x4000_0000)
+        .unwrap()
+        .is_all());
+    assert_eq!(
+        pt.query(va(0x4000_1000)).unwrap(),
+        (pa(0x8000_1000), RW | AD, PageSize::Size1G)
+    );
+    assert_eq!(stats.live(), 1);
+}
+
+#[test]
+fn fork_cow_shares_pages() {
+    let (mut pt, stats) = new_page_table::<Sv39MetaData, Rv64PTE>();
+    let user_rw = RW | MappingFlags::USER;
+    pt.map(va(0x1000), pa(0x1000), PageSize::Size4K, user_rw)
+        .unwrap()
+        .ignore();
+    pt.map(va(0x2000), pa(0x2000), PageSize::Size4K, MappingFlags::READ)
+        .unwrap()
+        .ignore();
+    assert_eq!(stats.live(), 3);
+
+    let (mut child, flush) = pt.fork_cow().unwrap();
+    assert!(flush.is_all());
+    assert_eq!(stats.live(), 6);
+    let cow = MappingFlags::READ | MappingFlags::USER | MappingFlags::COW | AD;
+    for table in [&pt, &child] {
+        assert_eq!(
+            table.query(va(0x1000)).unwrap(),
+            (pa(0x1000), cow, PageSize::Size4K)
+        );
+        assert_eq!(
+            table.query(va(0x2000)).unwrap(),
+            (pa(0x2000), MappingFlags::READ | AD, PageSize::Size4K)
+        );
+    }
+
+    child
+        .resolve_cow_fault(va(0x1234), pa(0x5000))
+        .unwrap()
+        .1
+        .ignore();
+    assert_eq!(
+        child.query(va(0x1000)).unwrap(),
+        (pa(0x5000), user_rw | AD, PageSize::Size4K)
+    );
+    assert_eq!(pt.query(va(0x1000)).unwrap().1, cow);
+    assert!(matches!(
+        child.resolve_cow_fault(va(0x2000), pa(0x6000)),
+        Err(PagingError::InvalidPermission)
+    ));
+
+    drop(child);
+    assert_eq!(stats.live(), 3);
+
+    // the child reclaims tables and tracks accesses like the parent
+    pt.set_deferred_reclaim(true);
+    pt.set_access_tracking(true);
+    let (mut child, flush) = pt.fork_cow().unwrap();
+    flush.ignore();
+    child
+        .map(va(0x4000_0000), pa(0x3000), PageSize::Size4K, RW)
+        .unwrap()
+        .ignore();
+    assert_eq!(child.query(va(0x4000_0000)).unwrap().1, RW);
+    assert_eq!(stats.live(), 8);
+    child.unmap(va(0x4000_0000)).unwrap().2.ignore();
+    assert_eq!(stats.live(), 8);
+    child.release_reclaimed_tables();
+    assert_eq!(stats.live(), 6);
+}
+
+#[test]
+fn rollback_frees_tables() {
+    let (mut pt, stats) = new_page_table::<Sv39MetaData, Rv64PTE>();
+    // the second page needs another last-level table
+    stats.set_limit(3);
+    let err = pt
+        .map_region(va(0x1f_f000), pa(0x1000), 0x2000, RW, false)
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        MapRegionError {
+            error: PagingError::NoMemory,
+            mapped: 0x1000,
+            rollback_error: None,
+            ..
+        }
+    ));
+    assert!(err.flush.is_all());
+    assert!(matches!(
+        pt.query(va(0x1f_f000)),
+        Err(PagingError::NotMapped)
+    ));
+    assert_eq!(stats.live(), 1);
+
+    stats.set_limit(usize::MAX);
+    pt.map_region(va(0x1f_f000), pa(0x1000), 0x2000, RW, false)
+        .unwrap()
+        .ignore();
+    assert_eq!(stats.live(), 4);
+}
+
+/// Maps and protects pages without any access permission, which must stay
+/// mapped.
+fn check_prot_none<M: PagingMetaData, PTE: GenericPTE + 'static>()
+where
+    PTE::Atomic: Default,
+{
+    let (mut pt, stats) = new_page_table::<M, PTE>();
+    let perms = RW | MappingFlags::EXECUTE;
+
+    pt.map(va(0x1000), pa(0), PageSize::Size4K, MappingFlags::empty())
+        .unwrap()
+        .ignore();
+    let (paddr, flags, size) = pt.query(va(0x1000)).unwrap();
+    assert_eq!((paddr, size), (pa(0), PageSize::Size4K));
+    assert!(!flags.intersects(perms));
+    assert!(matches!(
+        pt.map(va(0x1000), pa(0x1000), PageSize::Size4K, RW),
+        Err(PagingError::AlreadyMapped)
+    ));
+    pt.unmap(va(0x1000)).unwrap().2.ignore();
+    assert!(matches!(pt.query(va(0x1000)), Err(PagingError::NotMapped)));
+
+    pt.map_region(va(0x20_0000), pa(0x4000_0000), 0x40_0000, RW, true)
+        .unwrap()
+        .ignore();
+    pt.protect_region(va(0x20_0000), 0x40_0000, MappingFlags::empty())
+        .unwrap()
+        .ignore();
+    let (paddr, flags, size) = pt.query(va(0x20_1000)).unwrap();
+    assert_eq!((paddr, size), (pa(0x4000_1000), PageSize::Size2M));
+    assert!(!flags.intersects(perms));
+    assert!(matches!(
+        pt.map(va(0x20_0000), pa(0), PageSize::Size4K, RW),
+        Err(PagingError::MappedToHugePage)
+    ));
+
+    // the split pages stay mapped
+    pt.protect_region(va(0x20_1000), 0x1000, RW)
+        .unwrap()
+        .ignore();
+    assert_eq!(pt.query(va(0x20_1000)).unwrap().1 & perms, RW);
+    let (paddr, flags, size) = pt.query(va(0x20_2000)).unwrap();
+    assert_eq!((paddr, size), (pa(0x4000_2000), PageSize::Size4K));
+    assert!(!flags.intersects(perms));
+    pt.protect_region(va(0x20_0000), 0x20_0000, RW)
+        .unwrap()
+        .ignore();
+    assert_eq!(pt.query(va(0x20_2000)).unwrap().1 & perms, RW);
+
+    pt.unmap_region(va(0x20_0000), 0x40_0000).unwrap().ignore();
+    assert!(matches!(
+        pt.query(va(0x40_0000)),
+        Err(PagingError::NotMapped)
+    ));
+    assert_eq!(stats.live(), 1);
+}
+
+#[test]
+fn prot_none_pages() {
+    check_prot_none::<Sv39MetaData, Rv64PTE>();
+    check_prot_none::<X64PagingMetaData, X64PTE>();
+    check_prot_none::<A64PagingMetaData, A64PTE>();
+    check_prot_none::<LA64MetaData, LA64PTE>();
+}
+
+/// Stores all the supported software-defined flags in present and not present
+/// entries.
+fn check_software_bits<M: PagingMetaData, PTE: GenericPTE + 'static>()
+where
+    PTE::Atomic: Default,
+{
+    let (mut pt, _) = new_page_table::<M, PTE>();
+    let software = MappingFlags::software(PTE::SOFTWARE_BITS);
+    let perms = RW | MappingFlags::EXECUTE;
+    assert!(!software.is_empty());
+
+    pt.map(va(0x1000), pa(0x1000), PageSize::Size4K, RW | software)
+        .unwrap()
+        .ignore();
+    pt.map(
+        va(0x20_0000),
+        pa(0x20_0000),
+        PageSize::Size2M,
+        RW | software,
+    )
+    .unwrap()
+    .ignore();
+    for vaddr in [va(0x1000), va(0x20_0000)] {
+        let flags = pt.query(vaddr).unwrap().1;
+        assert_eq!(flags & (perms | software), RW | software);
+
+        // e.g. swapped out, with the swap slot in the physical address
+        pt.update(vaddr, Some(pa(0x7000_0000)), Some(software))
+            .unwrap()
+            .2
+            .ignore();
+        let (paddr, flags, size) = pt.query(vaddr).unwrap();
+        assert_eq!(paddr, pa(0x7000_0000));
+        assert_eq!(flags & (perms | software), software);
+
+        pt.protect_region(vaddr, size as usize, RW | software)
+            .unwrap()
+            .ignore();
+        let flags = pt.query(vaddr).unwrap().1;
+        assert_eq!(flags & (perms | software), RW | software);
+    }
+    if software != MappingFlags::software(usize::MAX) {
+        assert!(matches!(
+            pt.update(va(0x1000), None, Some(MappingFlags::software(usize::MAX))),
+            Err(PagingError::InvalidPermission)
+        ));
+    }
+}
+
+#[test]
+fn software_bits() {
+    check_software_bits::<Sv39MetaData, Rv64PTE>();
+    check_software_bits::<X64PagingMetaData, X64PTE>();
+    check_software_bits::<A64PagingMetaData, A64PTE>();
+    check_software_bits::<LA64MetaData, LA64PTE>();
+}
+
+#[test]
+fn region_errors_carry_flush() {
+    let (mut pt, stats) = new_page_table::<Sv39MetaData, Rv64PTE>();
+    pt.map_region(va(0x1000), pa(0x1000), 0x2000, RW, false)
+        .unwrap()
+        .ignore();
+    let err = pt.unmap_region(va(0x1000), 0x3000).unwrap_err();
+    assert!(matches!(err.error, PagingError::NotMapped));
+    assert_eq!(err.done, 0x2000);
+    // the emptied tables are reclaimed as well
+    assert!(err.flush.is_all());
+    assert_eq!(stats.live(), 1);
+
+    pt.map_region(va(0x1000), pa(0x1000), 0x1000, RW, false)
+        .unwrap()
+        .ignore();
+    pt.map(va(0x20_0000), pa(0x20_0000), PageSize::Size2M, RW)
+        .unwrap()
+        .ignore();
+    stats.set_limit(stats.live());
+    let err = pt
+        .protect_region(va(0x1000), 0x20_1000, MappingFlags::READ)
+        .unwrap_err();
+    assert!(matches!(err.error, PagingError::NoMemory));
+    assert_eq!(err.done, 0x1f_f000);
+    assert_eq!(
+        err.flush.pages().collect::<alloc::vec::Vec<_>>(),
+        [va(0x1000)]
+    );
+    assert_eq!(pt.query(va(0x1000)).unwrap().1, MappingFlags::READ | AD);
+    stats.set_limit(usize::MAX);
+
+    // dropping a cursor reclaims the tables it has emptied
+    let mut cursor = pt.cursor_mut(va(0x1000)..=va(0x1fff)).unwrap();
+    cursor.unmap().unwrap().2.ignore();
+    drop(cursor);
+    assert_eq!(stats.live(), 2);
+}
+
+#[test]
+fn top_of_address_space() {
+    const TOP: usize = 0xffff_ffff_ffe0_0000;
+    let (mut pt, stats) = new_page_table::<Sv39MetaData, Rv64PTE>();
+    pt.map_region(va(TOP), pa(0x20_0000), 0x20_0000, RW, true)
+        .unwrap()
+        .ignore();
+    assert_eq!(
+        pt.query(va(usize::MAX)).unwrap(),
+        (pa(0x3f_ffff), RW | AD, PageSize::Size2M)
+    );
+
+    pt.protect_region(va(usize::MAX - 0xfff), 0x1000, MappingFlags::READ)
+        .unwrap()
+        .ignore();
+    assert_eq!(pt.query(va(usize::MAX)).unwrap().1, MappingFlags::READ | AD);
+    assert!(!pt.collapse_region(va(TOP), 0x20_0000).unwrap().is_all());
+    pt.protect_region(va(TOP), 0x20_0000, RW).unwrap().ignore();
+    assert!(pt.collapse_region(va(TOP), 0x20_0000).unwrap().is_all());
+    assert_eq!(pt.query(va(TOP)).unwrap().2, PageSize::Size2M);
+
+    let (pages, flush) = pt.test_and_clear_dirty_region(va(TOP), 0x20_0000).unwrap();
+    flush.ignore();
+    assert_eq!(pages, [(va(TOP), PageSize::Size2M)]);
+
+    pt.unmap_region(va(TOP), 0x20_0000).unwrap().ignore();
+    assert!(matches!(pt.query(va(TOP)), Err(PagingError::NotMapped)));
+    assert_eq!(stats.live(), 1);
+
+    let mut cursor = pt.cursor_mut(va(TOP)..=va(usize::MAX)).unwrap();
+    cursor.seek(va(usize::MAX - 0xfff)).unwrap();
+    cursor
+        .map_page(pa(0x1000), PageSize::Size4K, RW)
+        .unwrap()
+        .ignore();
+    assert!(!cursor.in_range());
+    cursor.finish().ignore();
+    let mut cursor = pt.cursor_mut(va(TOP)..=va(usize::MAX)).unwrap();
+    assert_eq!(
+        cursor.next(),
+        Some((
+            va(usize::MAX - 0xfff),
+            pa(0x1000),
+            RW | AD,
+            PageSize::Size4K
+        ))
+    );
+    assert_eq!(cursor.next(), None);
+}
+
+#[test]
+fn invalid_addresses() {
+    let (mut pt, _) = new_page_table::<Sv39MetaData, Rv64PTE>();
+    // not sign-extended from bit 38
+    assert!(matches!(
+        pt.map(va(0x40_0000_0000), pa(0x1000), PageSize::Size4K, RW),
+        Err(PagingError::InvalidAddress(0x40_0000_0000))
+    ));
+    // crosses the hole in the middle of the address space
+    assert!(matches!(
+        pt.map_region(va(0x3f_ffff_f000), pa(0x1000), 0x2000, RW, false),
+        Err(MapRegionError {
+            error: PagingError::InvalidAddress(0x40_0000_0fff),
+            ..
+        })
+    ));
+    // wraps around the top of the address space
+    assert!(matches!(
+        pt.unmap_region(va(usize::MAX - 0xfff), 0x2000),
+        Err(e) if matches!(e.error, PagingError::InvalidAddress(0xfff))
+    ));
+    assert!(matches!(
+        pt.cursor_mut(va(0x2000)..=va(0x1fff)),
+        Err(PagingError::InvalidAddress(0x1fff))
+    ));
+    // beyond the 56-bit physical addresses
+    assert!(matches!(
+        pt.map(va(0x1000), pa(1 << 56), PageSize::Size4K, RW),
+        Err(PagingError::InvalidAddress(0x100_0000_0000_0000))
+    ));
+
+    pt.map(
+        va(0x1000),
+        pa(0x1000),
+        PageSize::Size4K,
+        RW | MappingFlags::USER,
+    )
+    .unwrap()
+    .ignore();
+    let (_child, flush) = pt.fork_cow().unwrap();
+    flush.ignore();
+    assert!(matches!(
+        pt.resolve_cow_fault(va(0x1000), pa(1 << 56)),
+        Err(PagingError::InvalidAddress(0x100_0000_0000_0000))
+    ));
+    assert!(pt.query(va(0x1000)).unwrap().1.contains(MappingFlags::COW));
+}
+
+#[test]
+fn cursor_range_errors() {
+    let (mut pt, _) = new_page_table::<Sv39MetaData, Rv64PTE>();
+    let mut cursor = pt.cursor_mut(va(0x1000)..=va(0x1f_ffff)).unwrap();
+    assert!(matches!(
+        cursor.map_page(pa(0), PageSize::Size2M, RW),
+        Err(PagingError::NotAligned)
+    ));
+    assert!(matches!(
+        cursor.seek(va(0x20_0000)),
+        Err(PagingError::InvalidAddress(0x20_0000))
+    ));
+    cursor.seek(va(0x1f_f000)).unwrap();
+    assert!(matches!(
+        cursor.seek(va(0x1000)),
+        Err(PagingError::InvalidAddress(0x1000))
+    ));
+    cursor
+        .map_page(pa(0x1000), PageSize::Size4K, RW)
+        .unwrap()
+        .ignore();
+    assert!(matches!(
+        cursor.map_page(pa(0x2000), PageSize::Size4K, RW),
+        Err(PagingError::InvalidAddress(0x20_0000))
+    ));
+    cursor.finish().ignore();
+
+    // huge pages crossing the boundary of the range are not changed
+    pt.map(va(0x20_0000), pa(0x20_0000), PageSize::Size2M, RW)
+        .unwrap()
+        .ignore();
+    let mut cursor = pt.cursor_mut(va(0x20_1000)..=va(0x3f_ffff)).unwrap();
+    assert!(matches!(
+        cursor.unmap(),
+        Err(PagingError::InvalidAddress(0x20_0000))
+    ));
+    assert!(matches!(
+        cursor.protect(MappingFlags::READ),
+        Err(PagingError::InvalidAddress(0x20_0000))
+    ));
+    cursor.finish().ignore();
+    let mut cursor = pt.cursor_mut(va(0x20_0000)..=va(0x2f_ffff)).unwrap();
+    assert!(matches!(
+        cursor.test_and_clear(MappingFlags::ACCESSED),
+        Err(PagingError::InvalidAddress(0x3f_ffff))
+    ));
+    cursor.finish().ignore();
+    assert_eq!(pt.query(va(0x20_0000)).unwrap().1, RW | AD);
+
+    // but split by the region operations
+    let (pages, flush) = pt
+        .test_and_clear_accessed_region(va(0x20_1000), 0x1000)
+        .unwrap();
+    flush.ignore();
+    assert_eq!(pages, [(va(0x20_1000), PageSize::Size4K)]);
+    assert_eq!(pt.query(va(0x20_0000)).unwrap().1, RW | AD);
+    assert_eq!(
+        pt.query(va(0x20_1000)).unwrap(),
+        (
+            pa(0x20_1000),
+            MappingFlags::WRITE | MappingFlags::READ | MappingFlags::DIRTY,
+            PageSize::Size4K
+        )
+    );
+}
+
+fn check_dirty_log<M: PagingMetaData, PTE: GenericPTE + 'static>()
+where
+    PTE::Atomic: Default,
+{
+    let (mut pt, _) = new_page_table::<M, PTE>();
+    pt.map(va(0x1000), pa(0x1000), PageSize::Size4K, RW)
+        .unwrap()
+        .ignore();
+    pt.map(va(0x2000), pa(0x2000), PageSize::Size4K, MappingFlags::READ)
+        .unwrap()
+        .ignore();
+    pt.map(va(0x20_0000), pa(0x20_0000), PageSize::Size2M, RW)
+        .unwrap()
+        .ignore();
+
+    pt.start_dirty_log(va(0), 0x40_0000, true).unwrap().ignore();
+    assert_eq!(
+        pt.query(va(0x20_5000)).unwrap(),
+        (pa(0x20_5000), RW | MappingFlags::ACCESSED, PageSize::Size4K)
+    );
+    let (bitmap, flush) = pt.collect_dirty_log(va(0), 0x40_0000).unwrap();
+    assert!(flush.is_empty());
+    assert_eq!(bitmap, [0; 16]);
+
+    // the writes that the MMU or the fault handler marks
+    pt.resolve_dirty_fault(va(0x1000)).unwrap().1.ignore();
+    pt.resolve_dirty_fault(va(0x20_5000)).unwrap().1.ignore();
+    assert!(matches!(
+        pt.resolve_dirty_fault(va(0x2000)),
+        Err(PagingError::InvalidPermission)
+    ));
+    assert_eq!(pt.query(va(0x1000)).unwrap().1, RW | AD);
+
+    let (bitmap, flush) = pt.collect_dirty_log(va(0), 0x40_0000).unwrap();
+    assert_eq!(
+        flush.pages().collect::<alloc::vec::Vec<_>>(),
+        [va(0x1000), va(0x20_5000)]
+    );
+    let mut expected = [0; 16];
+    expected[0] = 1 << 1;
+    expected[8] = 1 << 5;
+    assert_eq!(bitmap, expected);
+    assert_eq!(pt.query(va(0x1000)).unwrap().1, RW | MappingFlags::ACCESSED);
+    assert_eq!(pt.collect_dirty_log(va(0), 0x40_0000).unwrap().0, [0; 16]);
+}
+
+#[test]
+fn dirty_log() {
+    check_dirty_log::<Sv39MetaData, Rv64PTE>();
+    check_dirty_log::<X64PagingMetaData, X64PTE>();
+    check_dirty_log::<A64PagingMetaData, A64PTE>();
+    check_dirty_log::<LA64MetaData, LA64PTE>();
+
+    // the pages are write-protected for the MMU without hardware dirty bits
+    let (mut pt, _) = new_page_table::<A64PagingMetaData, A64PTE>();
+    pt.map(va(0x1000), pa(0x1000), PageSize::Size4K, RW)
+        .unwrap()
+        .ignore();
+    pt.start_dirty_log(va(0x1000), 0x1000, false)
+        .unwrap()
+        .ignore();
+    let (slot, _) = pt.get_entry(va(0x1000)).unwrap();
+    assert_eq!(
+        A64PTE::load(slot).bits() & (1 << 7 | 1 << 51),
+        1 << 7 | 1 << 51
+    );
+    let (mut pt, _) = new_page_table::<LA64MetaData, LA64PTE>();
+    pt.map(va(0x1000), pa(0x1000), PageSize::Size4K, RW)
+        .unwrap()
+        .ignore();
+    pt.start_dirty_log(va(0x1000), 0x1000, false)
+        .unwrap()
+        .ignore();
+    let (slot, _) = pt.get_entry(va(0x1000)).unwrap();
+    assert_eq!(LA64PTE::load(slot).bits() & (1 << 1 | 1 << 8), 1 << 8);
+}
+
+#[test]
+fn shared_tables() {
+    let (mut kernel, stats) = new_page_table::<Sv39MetaData, Rv64PTE>();
+    kernel
+        .map(va(0x1_4000_0000), pa(0x4000_0000), PageSize::Size1G, RW)
+        .unwrap()
+        .ignore();
+    let alloc = kernel.allocator().clone();
+    let mut user = MockPageTable::try_new_with_shared_in(
+        &mut kernel,
+        va(0x8000_0000)..=va(0xbfff_ffff),
+        alloc.clone(),
+    )
+    .unwrap();
+    let other = MockPageTable::try_new_with_shared_in(
+        &mut kernel,
+        va(0x1_4000_0000)..=va(0x1_7fff_ffff),
+        alloc,
+    )
+    .unwrap();
+    assert_eq!(stats.live(), 4);
+
+    // the pages mapped later by the kernel are seen through the shared tables
+    kernel
+        .map(va(0x8000_1000), pa(0x1000), PageSize::Size4K, RW)
+        .unwrap()
+        .ignore();
+    let kernel_entry = kernel.entry_at(kernel.root_paddr(), 2);
+    assert_eq!(
+        user.entry_at(user.root_paddr(), 2).paddr(),
+        kernel_entry.paddr()
+    );
+    assert!(matches!(
+        user.query(va(0x8000_1000)),
+        Err(PagingError::SharedMapping)
+    ));
+    assert!(matches!(
+        user.unmap_region(va(0x8000_0000), 0x2000),
+        Err(e) if matches!(e.error, PagingError::SharedMapping)
+    ));
+
+    // only the shared root entries are kept, not those between them
+    kernel
+        .map(va(0xc000_0000), pa(0x1000), PageSize::Size4K, RW)
+        .unwrap()
+        .ignore();
+    assert_eq!(stats.live(), 7);
+    kernel
+        .unmap_region(va(0xc000_0000), 0x1000)
+        .unwrap()
+        .ignore();
+    assert_eq!(stats.live(), 5);
+    // the shared table stays, the table below it is reclaimed
+    kernel
+        .unmap_region(va(0x8000_1000), 0x1000)
+        .unwrap()
+        .ignore();
+    assert_eq!(stats.live(), 4);
+    assert_eq!(
+        user.entry_at(user.root_paddr(), 2).paddr(),
+        kernel.entry_at(kernel.root_paddr(), 2).paddr()
+    );
+
+    // the shared huge page is not split
+    assert!(matches!(
+        kernel.protect_region(va(0x1_4000_0000), 0x1000, MappingFlags::READ),
+        Err(e) if matches!(e.error, PagingError::SharedMapping)
+    ));
+    assert_eq!(kernel.query(va(0x1_4000_0000)).unwrap().2, PageSize::Size1G);
+
+    drop(user);
+    drop(other);
+    assert_eq!(stats.live(), 2);
+}
+
+#[test]
+fn sv57_pages() {
+    let (mut pt, stats) = new_page_table::<Sv57MetaData, Rv64PTE>();
+    pt.map(
+        va(0x80_0000_0000),
+        pa(0x80_0000_0000),
+        PageSize::Size512G,
+        RW,
+    )
+    .unwrap()
+    .ignore();
+    assert_eq!(
+        pt.query(va(0xff_ffff_ffff)).unwrap(),
+        (pa(0xff_ffff_ffff), RW | AD, PageSize::Size512G)
+    );
+    assert_eq!(stats.live(), 2);
+
+    // beyond the 48-bit address space, one table per level
+    const HIGH: usize = 0x00ff_ffff_ffff_f000;
+    pt.map(va(HIGH), pa(0x1000), PageSize::Size4K, RW)
+        .unwrap()
+        .ignore();
+    assert_eq!(
+        pt.query(va(HIGH)).unwrap(),
+        (pa(0x1000), RW | AD, PageSize::Size4K)
+    );
+    assert_eq!(stats.live(), 6);
+    assert!(matches!(
+        pt.map(va(0x0100_0000_0000_0000), pa(0x1000), PageSize::Size4K, RW),
+        Err(PagingError::InvalidAddress(_))
+    ));
+
+    // splitting a 512G page needs a table for each of the lower levels
+    pt.unmap_region(va(0x80_0000_0000), 0x1000)
+        .unwrap()
+        .ignore();
+    assert_eq!(
+        pt.query(va(0x80_0000_1000)).unwrap(),
+        (pa(0x80_0000_1000), RW | AD, PageSize::Size4K)
+    );
+    assert_eq!(pt.query(va(0x80_4000_0000)).unwrap().2, PageSize::Size1G);
+    assert_eq!(stats.live(), 9);
+
+    let (mut pt, _) = new_page_table::<X64PagingMetaData, X64PTE>();
+    assert!(matches!(
+        pt.map(va(0), pa(0), PageSize::Size512G, RW),
+        Err(PagingError::UnsupportedPageSize)
+    ));
+    assert!(matches!(
+        pt.map(va(0), pa(0), PageSize::Size4M, RW),
+        Err(PagingError::UnsupportedPageSize)
+    ));
+}
+
+#[test]
+fn x86_64_level5_addresses() {
+    // canonical addresses are sign extended from bit 56 instead of bit 47
+    const HIGH: usize = 0xff00_0000_0000_0000;
+    assert!(X64Level5MetaData::vaddr_is_valid(HIGH));
+    assert!(X64Level5MetaData::vaddr_is_valid(0x00ff_ffff_ffff_ffff));
+    assert!(!X64Level5MetaData::vaddr_is_valid(0x0100_0000_0000_0000));
+    assert!(!X64Level5MetaData::vaddr_is_valid(0xfe00_0000_0000_0000));
+    assert!(!X64PagingMetaData::vaddr_is_valid(HIGH));
+
+    let (mut pt, stats) = new_page_table::<X64Level5MetaData, X64PTE>();
+    pt.map(va(HIGH), pa(0x4000_0000), PageSize::Size1G, RW)
+        .unwrap()
+        .ignore();
+    pt.map(va(0x1000), pa(0x1000), PageSize::Size4K, RW)
+        .unwrap()
+        .ignore();
+    assert_eq!(
+        pt.query(va(HIGH + 0x1000)).unwrap(),
+        (pa(0x4000_1000), RW | AD, PageSize::Size1G)
+    );
+    assert_eq!(
+        pt.query(va(0x1000)).unwrap(),
+        (pa(0x1000), RW | AD, PageSize::Size4K)
+    );
+    assert_eq!(stats.live(), 7);
+
+    let (mut pt, _) = new_page_table::<X64PagingMetaData, X64PTE>();
+    assert!(matches!(
+        pt.map(va(HIGH), pa(0x4000_0000), PageSize::Size1G, RW),
+        Err(PagingError::InvalidAddress(_))
+    ));
+}
+
+#[test]
+fn root_registers() {
+    let (pt, _) = new_page_table::<Sv39MetaData, Rv64PTE>();
+    assert_eq!(pt.root_paddr(), pa(0x1000));
+    assert_eq!(pt.root_register(0x1234), 8 << 60 | 0x1234 << 44 | 1);
+    assert_eq!(
+        Sv48MetaData::root_register(0x80_0000_0000, 0xffff),
+        9 << 60 | 0xffff << 44 | 0x800_0000
+    );
+    assert_eq!(Sv57MetaData::root_register(0x2000, 0), 10 << 60 | 2);
+
+    // PCIDs are 12 bits wide
+    let (pt, _) = new_page_table::<X64PagingMetaData, X64PTE>();
+    assert_eq!(pt.root_register(0xabc), 0x1abc);
+    assert_eq!(X64Level5MetaData::root_register(0x2000, 0xf001), 0x2001);
+
+    let (pt, _) = new_page_table::<A64PagingMetaData, A64PTE>();
+    assert_eq!(pt.root_register(0xffff), 0xffff << 48 | 0x1000);
+    let (pt, _) = new_page_table::<LA64MetaData, LA64PTE>();
+    assert_eq!(pt.root_register(0xffff), 0x1000);
+}
+
+#[test]
+fn linear_tables() {
+    let stats = Stats::new();
+    let alloc = LinearMockIf::<Rv64PTE>::new(stats.clone(), 3);
+    let mut pt = PageTable64::<Sv39MetaData, Rv64PTE, _, LinearTables>::try_new_in(alloc).unwrap();
+    pt.map_region(va(0x1000), pa(0x1000), 0x2000, RW, false)
+        .unwrap()
+        .ignore();
+    assert_eq!(
+        pt.query(va(0x2000)).unwrap(),
+        (pa(0x2000), RW | AD, PageSize::Size4K)
+    );
+    assert_eq!(stats.live(), 3);
+
+    // the pool is exhausted
+    assert!(matches!(
+        pt.map(va(0x20_0000), pa(0x1000), PageSize::Size4K, RW),
+        Err(PagingError::NoMemory)
+    ));
+    assert_eq!(stats.live(), 3);
+
+    pt.unmap_region(va(0x1000), 0x2000).unwrap().ignore();
+    assert_eq!(stats.live(), 1);
+    drop(pt);
+    assert_eq!(stats.live(), 0);
+}
+
+/// Sets the raw `bits` of the entry that maps `vaddr`, like the MMU does.
+fn touch(pt: &MockPageTable, vaddr: usize, bits: u64) {
+    let (slot, _) = pt.get_entry(va(vaddr)).unwrap();
+    Rv64PTE::store(slot, Rv64PTE::from_bits(Rv64PTE::load(slot).bits() | bits));
+}
+
+#[test]
+fn access_tracking() {
+    const A: u64 = 1 << 6;
+    const D: u64 = 1 << 7;
+    let (mut pt, _) = new_page_table::<Sv39MetaData, Rv64PTE>();
+    pt.set_access_tracking(true);
+    pt.map_region(va(0x1000), pa(0x1000), 0x2000, RW, false)
+        .unwrap()
+        .ignore();
+    pt.map(va(0x20_0000), pa(0x20_0000), PageSize::Size2M, RW)
+        .unwrap()
+        .ignore();
+    assert_eq!(pt.query(va(0x1000)).unwrap().1, RW);
+
+    touch(&pt, 0x1000, A);
+    let (accessed, flush) = pt.test_and_clear_accessed(va(0x1000)).unwrap();
+    assert!(accessed);
+    assert_eq!(flush.pages().collect::<alloc::vec::Vec<_>>(), [va(0x1000)]);
+    let (accessed, flush) = pt.test_and_clear_accessed(va(0x1000)).unwrap();
+    assert!(!accessed && flush.is_empty());
+    assert!(matches!(
+        pt.test_and_clear_dirty(va(0x3000)),
+        Err(PagingError::NotMapped)
+    ));
+
+    // changing the flags keeps the bits
+    touch(&pt, 0x2000, A | D);
+    pt.protect_region(va(0x2000), 0x1000, MappingFlags::READ)
+        .unwrap()
+        .ignore();
+    assert_eq!(pt.query(va(0x2000)).unwrap().1, MappingFlags::READ | AD);
+
+    touch(&pt, 0x20_0000, A);
+    let (pages, flush) = pt.test_and_clear_accessed_region(va(0), 0x40_0000).unwrap();
+    flush.ignore();
+    assert_eq!(
+        pages,
+        [
+            (va(0x2000), PageSize::Size4K),
+            (va(0x20_0000), PageSize::Size2M)
+        ]
+    );
+    let (pages, flush) = pt.test_and_clear_dirty_region(va(0), 0x40_0000).unwrap();
+    flush.ignore();
+    assert_eq!(pages, [(va(0x2000), PageSize::Size4K)]);
+    assert_eq!(pt.query(va(0x2000)).unwrap().1, MappingFlags::READ);
+
+    pt.set_access_tracking(false);
+    pt.map(va(0x3000), pa(0x3000), PageSize::Size4K, RW)
+        .unwrap()
+        .ignore();
+    assert_eq!(pt.query(va(0x3000)).unwrap().1, RW | AD);
+}
@@ -9,17 +9,38 @@ mod page_table_entry;
 extern crate log;
 extern crate alloc;
 
+mod aarch64;
+mod bits32;
 mod bits64;
+mod loongarch64;
+#[cfg(test)]
+mod mock;
 mod riscv;
+mod store;
+mod tlb;
+mod walk;
+mod x86_64;
 
 use alloc::boxed::Box;
 
+pub use aarch64::*;
+pub use loongarch64::*;
 use memory_addr::{PhysAddr, VirtAddr};
 #[doc(no_inline)]
-pub use page_table_entry::{riscv::Rv64PTE, GenericPTE, MappingFlags};
+pub use page_table_entry::{
+    aarch64::{MemAttr, A64PTE},
+    loongarch64::LA64PTE,
+    riscv::{Rv32PTE, Rv64PTE},
+    x86_64::X64PTE,
+    AtomicEntry, AtomicU64Entry, GenericPTE, MappingFlags,
+};
 pub use riscv::*;
+pub use x86_64::*;
 
-pub use self::bits64::{PageTable64, ENTRY_COUNT};
+pub use self::bits32::PageTable32;
+pub use self::bits64::{PageTable64, PageTableCursor, ENTRY_COUNT};
+pub use self::store::{LinearTables, TableStore, TrackedTables};
+pub use self::tlb::{TlbFlush, TlbFlushToken};
 
 /// The error type for page table operation failures.
 #[derive(Debug)]
@@ -28,6 +49,9 @@ pub enum PagingError {
     NoMemory,
     /// The address is not aligned to the page size.
     NotAligned,
+    /// The page size cannot be mapped by the page table, or is larger than
+    /// [`PagingMetaData::MAX_PAGE_SIZE`].
+    UnsupportedPageSize,
     /// The mapping is not present.
     NotMapped,
     /// The mapping is already present.
@@ -37,13 +61,80 @@ pub enum PagingError {
     MappedToHugePage,
     /// The permission is invalid.
     InvalidPermission,
+    /// The mapping is in the tables shared from another page table, which
+    /// cannot be accessed through this one, or would have to be changed in
+    /// the root table entries shared with other page tables.
+    SharedMapping,
+    /// The virtual or physical address is out of the range supported by the
+    /// [`PagingMetaData`].
+    InvalidAddress(usize),
 }
 
 /// The specialized `Result` type for page table operations.
 pub type PagingResult<T = ()> = Result<T, PagingError>;
 
+/// The error returned when mapping a region fails half way, by
+/// [`PageTable64::map_region`] and [`PageTable32::map_region`].
+///
+/// The pages mapped before the failure are unmapped again, and the tables
+/// allocated for them are reclaimed, but they may already be cached by the
+/// hardware, so the TLB must still be flushed with [`MapRegionError::flush`].
+#[derive(Debug)]
+pub struct MapRegionError {
+    /// The error that stopped the mapping.
+    pub error: PagingError,
+    /// The number of bytes at the start of the region that were mapped before
+    /// the failure.
+    pub mapped: usize,
+    /// The error that stopped unmapping the mapped pages again, in which case
+    /// some of them stay mapped.
+    pub rollback_error: Option<PagingError>,
+    /// A token to flush the TLB entries of the pages that were mapped, and of
+    /// the reclaimed tables.
+    pub flush: TlbFlushToken,
+}
+
+impl From<PagingError> for MapRegionError {
+    fn from(error: PagingError) -> Self {
+        Self {
+            error,
+            mapped: 0,
+            rollback_error: None,
+            flush: TlbFlushToken::empty(),
+        }
+    }
+}
+
+/// The error returned when an operation on the pages of a region fails half
+/// way, e.g. by [`PageTable64::unmap_region`] and
+/// [`PageTable64::protect_region`].
+///
+/// The pages changed before the failure stay changed, so the TLB must still be
+/// flushed with [`RegionError::flush`].
+#[derive(Debug)]
+pub struct RegionError {
+    /// The error that stopped the operation.
+    pub error: PagingError,
+    /// The number of bytes at the start of the region that were done before
+    /// the failure.
+    pub done: usize,
+    /// A token to flush the TLB entries of the pages that were changed, and
+    /// of the reclaimed tables.
+    pub flush: TlbFlushToken,
+}
+
+impl From<PagingError> for RegionError {
+    fn from(error: PagingError) -> Self {
+        Self {
+            error,
+            done: 0,
+            flush: TlbFlushToken::empty(),
+        }
+    }
+}
+
 /// The **architecture-dependent** metadata that must be provided for
-/// [`PageTable64`].
+/// [`PageTable64`] and [`PageTable32`].
 #[const_trait]
 pub trait PagingMetaData: Sync + Send + Sized {
     /// The number of levels of the hardware page table.
@@ -52,6 +143,8 @@ pub trait PagingMetaData: Sync + Send + Sized {
     const PA_MAX_BITS: usize;
     /// The maximum number of bits of virtual address.
     const VA_MAX_BITS: usize;
+    /// The largest page size that can be mapped by a leaf entry.
+    const MAX_PAGE_SIZE: PageSize = PageSize::Size1G;
 
     /// The maximum physical address.
     const PA_MAX_ADDR: usize = (1 << Self::PA_MAX_BITS) - 1;
@@ -69,6 +162,33 @@ pub trait PagingMetaData: Sync + Send + Sized {
         let top_mask = usize::MAX << (Self::VA_MAX_BITS - 1);
         (vaddr & top_mask) == 0 || (vaddr & top_mask) == top_mask
     }
+
+    /// Whether all virtual addresses from `start` to `last` (inclusive) are
+    /// valid.
+    ///
+    /// The default requires both ends to be valid and to have the same top
+    /// bit, so that the range does not span the hole between the lower and
+    /// the higher half of the address space.
+    #[inline]
+    fn vaddr_range_is_valid(start: usize, last: usize) -> bool {
+        start <= last
+            && Self::vaddr_is_valid(start)
+            && Self::vaddr_is_valid(last)
+            && (start ^ last) >> (usize::BITS - 1) == 0
+    }
+
+    /// Encodes the value of the register that holds the root page table (e.g.
+    /// `satp`, `CR3` or `TTBR0_EL1`) from the physical address of the root
+    /// page table and the address space identifier `asid`.
+    ///
+    /// The bits of `asid` beyond the width of the ASID field of the register
+    /// are ignored. The default returns `root_paddr` and ignores `asid`, for
+    /// architectures that keep the ASID in a separate register.
+    #[inline]
+    fn root_register(root_paddr: usize, asid: u16) -> usize {
+        let _ = asid;
+        root_paddr
+    }
 }
 
 pub trait NotLeafPage<PTE: GenericPTE>: Send + Sync {
@@ -79,34 +199,74 @@ pub trait NotLeafPage<PTE: GenericPTE>: Send + Sync {
     /// Used to access the physical memory directly in page table implementation.
     fn virt_addr(&self) -> VirtAddr;
     /// Zero the page.
-    fn zero(&self);
-    fn as_pte_slice<'a>(&self) -> &'a [PTE];
-    fn as_pte_mut_slice<'a>(&self) -> &'a mut [PTE];
+    fn zero(&mut self);
+    /// Returns the entries of the page.
+    ///
+    /// The entries are read and written atomically, since the MMU may update
+    /// them while the page is used as a table.
+    fn as_pte_slice(&self) -> &[PTE::Atomic];
 }
 
 /// The low-level **OS-dependent** helpers that must be provided for
-/// [`PageTable64`].
+/// [`PageTable64`] and [`PageTable32`].
+///
+/// Each page table holds its own instance, so different page tables can
+/// allocate frames from different pools.
 pub trait PagingIf<PTE: GenericPTE>: Sized {
     /// Request to allocate a 4K-sized physical frame.
-    fn alloc_frame() -> Option<Box<dyn NotLeafPage<PTE>>>;
+    fn alloc_frame(&self) -> Option<Box<dyn NotLeafPage<PTE>>>;
+
+    /// Release a frame allocated by [`PagingIf::alloc_frame`], when the
+    /// page table is dropped, an empty table is reclaimed, or a failed
+    /// operation is rolled back.
+    ///
+    /// The default drops the frame.
+    fn dealloc_frame(&self, frame: Box<dyn NotLeafPage<PTE>>) {
+        drop(frame);
+    }
+}
+
+/// The low-level **OS-dependent** helpers for kernels that map all physical
+/// memory linearly, used by [`PageTable64`] with [`LinearTables`].
+///
+/// The tables are accessed through [`LinearPagingIf::phys_to_virt`], so the
+/// page table does not need to track them.
+pub trait LinearPagingIf<PTE: GenericPTE>: Sized {
+    /// Request to allocate a 4K-sized physical frame, returns its physical
+    /// address.
+    fn alloc_table_frame(&self) -> Option<PhysAddr>;
+    /// Release a frame allocated by [`LinearPagingIf::alloc_table_frame`].
+    fn dealloc_table_frame(&self, paddr: PhysAddr);
+    /// Returns the entries of the table at `paddr` through the linear mapping.
+    ///
+    /// The entries are read and written atomically, since the MMU may update
+    /// them concurrently. The frame must not be accessed through other ways
+    /// while the page table owns it.
+    fn phys_to_virt(&self, paddr: PhysAddr) -> &[PTE::Atomic];
 }
 
 /// The page sizes supported by the hardware page table.
+#[allow(clippy::enum_clike_unportable_variant)] // `Size512G` only exists on 64-bit targets
 #[repr(usize)]
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum PageSize {
     /// Size of 4 kilobytes (2<sup>12</sup> bytes).
     Size4K = 0x1000,
+    /// Size of 4 megabytes (2<sup>22</sup> bytes), used by 32-bit page tables.
+    Size4M = 0x40_0000,
     /// Size of 2 megabytes (2<sup>21</sup> bytes).
     Size2M = 0x20_0000,
     /// Size of 1 gigabytes (2<sup>30</sup> bytes).
     Size1G = 0x4000_0000,
+    /// Size of 512 gigabytes (2<sup>39</sup> bytes).
+    #[cfg(target_pointer_width = "64")]
+    Size512G = 0x80_0000_0000,
 }
 
 impl PageSize {
     /// Whether this page size is considered huge (larger than 4K).
     pub const fn is_huge(self) -> bool {
-        matches!(self, Self::Size1G | Self::Size2M)
+        !matches!(self, Self::Size4K)
     }
 }
 
@@ -0,0 +1,18 @@
+//! LoongArch64 specific page table structures.
+
+use crate::{page_table_entry::loongarch64::LA64PTE, PageTable64, PagingMetaData, TrackedTables};
+
+/// Metadata of LoongArch64 page tables (4 levels, 4K pages).
+#[derive(Clone, Copy)]
+pub struct LA64MetaData;
+
+impl const PagingMetaData for LA64MetaData {
+    const LEVELS: usize = 4;
+    const PA_MAX_BITS: usize = 48;
+    const VA_MAX_BITS: usize = 48;
+    // The ASID is kept in `CSR.ASID` rather than `PGDL`, so the default
+    // `root_register` applies.
+}
+
+/// LoongArch64 4-level page table.
+pub type LA64PageTable<I, S = TrackedTables<LA64PTE>> = PageTable64<LA64MetaData, LA64PTE, I, S>;
@@ -0,0 +1,163 @@
+//! A heap-backed [`PagingIf`] for the unit tests, with fake physical
+//! addresses.
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use memory_addr::{PhysAddr, VirtAddr};
+
+use crate::{GenericPTE, LinearPagingIf, NotLeafPage, PagingIf};
+
+/// Frame counters shared by a page table and its forks.
+pub(crate) struct Stats {
+    /// Frames currently allocated.
+    pub live: AtomicUsize,
+    /// Allocations fail once `live` reaches it.
+    pub limit: AtomicUsize,
+    /// Number of the next frame, from which its fake physical address is made.
+    pub next: AtomicUsize,
+    /// Number of times the entries of any table are accessed.
+    pub lookups: AtomicUsize,
+}
+
+impl Stats {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            live: AtomicUsize::new(0),
+            limit: AtomicUsize::new(usize::MAX),
+            next: AtomicUsize::new(0),
+            lookups: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn live(&self) -> usize {
+        self.live.load(Ordering::Relaxed)
+    }
+
+    pub fn lookups(&self) -> usize {
+        self.lookups.load(Ordering::Relaxed)
+    }
+
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.store(limit, Ordering::Relaxed);
+    }
+}
+
+/// A table on the heap, with a fake physical address.
+struct Frame<PTE: GenericPTE> {
+    paddr: PhysAddr,
+    entries: Box<[PTE::Atomic]>,
+    stats: Arc<Stats>,
+}
+
+impl<PTE: GenericPTE> NotLeafPage<PTE> for Frame<PTE> {
+    fn phys_addr(&self) -> PhysAddr {
+        self.paddr
+    }
+
+    fn virt_addr(&self) -> VirtAddr {
+        VirtAddr::from(self.paddr.as_usize())
+    }
+
+    fn zero(&mut self) {
+        for slot in self.entries.iter() {
+            PTE::store(slot, PTE::empty());
+        }
+    }
+
+    fn as_pte_slice(&self) -> &[PTE::Atomic] {
+        self.stats.lookups.fetch_add(1, Ordering::Relaxed);
+        &self.entries
+    }
+}
+
+/// Allocates 4K tables of `PTE` on the heap, counting them in [`Stats`].
+pub(crate) struct MockIf<PTE>(pub Arc<Stats>, PhantomData<PTE>);
+
+impl<PTE> MockIf<PTE> {
+    pub fn new(stats: Arc<Stats>) -> Self {
+        Self(stats, PhantomData)
+    }
+}
+
+impl<PTE> Clone for MockIf<PTE> {
+    fn clone(&self) -> Self {
+        Self::new(self.0.clone())
+    }
+}
+
+impl<PTE: GenericPTE + 'static> PagingIf<PTE> for MockIf<PTE>
+where
+    PTE::Atomic: Default,
+{
+    fn alloc_frame(&self) -> Option<Box<dyn NotLeafPage<PTE>>> {
+        if self.0.live() >= self.0.limit.load(Ordering::Relaxed) {
+            return None;
+        }
+        self.0.live.fetch_add(1, Ordering::Relaxed);
+        let n = self.0.next.fetch_add(1, Ordering::Relaxed) + 1;
+        let count = 0x1000 / core::mem::size_of::<PTE::Atomic>();
+        Some(Box::new(Frame::<PTE> {
+            paddr: PhysAddr::from(n << 12),
+            entries: (0..count).map(|_| Default::default()).collect(),
+            stats: self.0.clone(),
+        }))
+    }
+
+    fn dealloc_frame(&self, frame: Box<dyn NotLeafPage<PTE>>) {
+        self.0.live.fetch_sub(1, Ordering::Relaxed);
+        drop(frame);
+    }
+}
+
+/// Allocates 4K tables of `PTE` from a fixed pool of frames on the heap, as if
+/// the physical memory were mapped linearly, counting them in [`Stats`].
+///
+/// Released frames are not reused.
+pub(crate) struct LinearMockIf<PTE: GenericPTE> {
+    stats: Arc<Stats>,
+    frames: Vec<Box<[PTE::Atomic]>>,
+}
+
+impl<PTE: GenericPTE> LinearMockIf<PTE>
+where
+    PTE::Atomic: Default,
+{
+    pub fn new(stats: Arc<Stats>, count: usize) -> Self {
+        let entries = 0x1000 / core::mem::size_of::<PTE::Atomic>();
+        let frames = (0..count)
+            .map(|_| (0..entries).map(|_| Default::default()).collect())
+            .collect();
+        Self { stats, frames }
+    }
+}
+
+impl<PTE: GenericPTE> LinearPagingIf<PTE> for LinearMockIf<PTE> {
+    fn alloc_table_frame(&self) -> Option<PhysAddr> {
+        let n = self.stats.next.load(Ordering::Relaxed);
+        if n >= self.frames.len() || self.stats.live() >= self.stats.limit.load(Ordering::Relaxed) {
+            return None;
+        }
+        self.stats.live.fetch_add(1, Ordering::Relaxed);
+        self.stats.next.fetch_add(1, Ordering::Relaxed);
+        Some(PhysAddr::from((n + 1) << 12))
+    }
+
+    fn dealloc_table_frame(&self, _paddr: PhysAddr) {
+        self.stats.live.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn phys_to_virt(&self, paddr: PhysAddr) -> &[PTE::Atomic] {
+        self.stats.lookups.fetch_add(1, Ordering::Relaxed);
+        &self.frames[(paddr.as_usize() >> 12) - 1]
+    }
+}
+
+pub(crate) fn va(vaddr: usize) -> VirtAddr {
+    VirtAddr::from(vaddr)
+}
+
+pub(crate) fn pa(paddr: usize) -> PhysAddr {
+    PhysAddr::from(paddr)
+}
@@ -0,0 +1,287 @@
+//! AArch64 VMSAv8-64 translation table format descriptors.
+
+use core::fmt;
+
+use memory_addr::PhysAddr;
+
+use super::{AtomicU64Entry, GenericPTE, MappingFlags};
+
+bitflags::bitflags! {
+    /// Memory attribute fields in the VMSAv8-64 translation table format descriptors.
+    pub struct DescriptorAttr: u64 {
+        // Attribute fields in stage 1 VMSAv8-64 Block and Page descriptors:
+
+        /// Whether the descriptor is valid.
+        const VALID =       1 << 0;
+        /// The descriptor gives the address of the next level of translation table or 4KB page.
+        /// (not a 2M, 1G block)
+        const NON_BLOCK =   1 << 1;
+        /// Memory attributes index field.
+        const ATTR_INDX =   0b111 << 2;
+        /// Non-secure bit. For memory accesses from Secure state, specifies whether the output
+        /// address is in Secure or Non-secure memory.
+        const NS =          1 << 5;
+        /// Access permission: accessable at EL0.
+        const AP_EL0 =      1 << 6;
+        /// Access permission: read-only.
+        const AP_RO =       1 << 7;
+        /// Shareability: Inner Shareable (otherwise Outer Shareable).
+        const INNER =       1 << 8;
+        /// Shareability: Inner or Outer Shareable (otherwise Non-shareable).
+        const SHAREABLE =   1 << 9;
+        /// The Access flag.
+        const AF =          1 << 10;
+        /// The not global bit.
+        const NG =          1 << 11;
+        /// The dirty bit modifier. It is ignored by the MMU without hardware
+        /// dirty state management, and marks the writable pages that have not
+        /// been written, which are read-only until the first write.
+        const DBM =         1 <<  51;
+        /// Indicates that 16 adjacent translation table entries point to contiguous memory regions.
+        const CONTIGUOUS =  1 <<  52;
+        /// The Privileged execute-never field.
+        const PXN =         1 <<  53;
+        /// The Execute-never or Unprivileged execute-never field.
+        const UXN =         1 <<  54;
+        /// Software-defined: the page is copy-on-write.
+        const COW =         1 <<  55;
+        /// Software-defined: the page is shared.
+        const SHARED =      1 <<  56;
+        /// Software-defined: the page is pinned.
+        const PINNED =      1 <<  57;
+        /// Software-defined: the page is backed by the swap space.
+        const SWAP_BACKED = 1 <<  58;
+
+        // Next-level attributes in stage 1 VMSAv8-64 Table descriptors:
+
+        /// PXN limit for subsequent levels of lookup.
+        const PXN_TABLE =           1 << 59;
+        /// XN limit for subsequent levels of lookup.
+        const XN_TABLE =            1 << 60;
+        /// Access permissions limit for subsequent levels of lookup: access at EL0 not permitted.
+        const AP_NO_EL0_TABLE =     1 << 61;
+        /// Access permissions limit for subsequent levels of lookup: write access not permitted.
+        const AP_NO_WRITE_TABLE =   1 << 62;
+        /// For memory accesses from Secure state, specifies the Security state for subsequent
+        /// levels of lookup.
+        const NS_TABLE =            1 << 63;
+    }
+}
+
+/// The memory attributes index field in the descriptor, which is used to index
+/// into the MAIR (Memory Attribute Indirection Register).
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MemAttr {
+    /// Device-nGnRE memory
+    Device = 0,
+    /// Normal memory, inner and outer write-back non-transient read/write allocate.
+    Normal = 1,
+    /// Normal memory, inner and outer non-cacheable.
+    NormalNonCacheable = 2,
+}
+
+impl DescriptorAttr {
+    /// Constructs a descriptor from the memory index, leaving the other fields
+    /// empty.
+    pub const fn from_mem_attr(idx: MemAttr) -> Self {
+        let mut bits = (idx as u64) << 2;
+        if matches!(idx, MemAttr::Normal | MemAttr::NormalNonCacheable) {
+            bits |= Self::INNER.bits() | Self::SHAREABLE.bits();
+        }
+        Self::from_bits_truncate(bits)
+    }
+
+    /// Returns the memory attribute index field.
+    pub const fn mem_attr(&self) -> Option<MemAttr> {
+        let idx = (self.bits() & Self::ATTR_INDX.bits()) >> 2;
+        Some(match idx {
+            0 => MemAttr::Device,
+            1 => MemAttr::Normal,
+            2 => MemAttr::NormalNonCacheable,
+            _ => return None,
+        })
+    }
+}
+
+impl MemAttr {
+    /// The MAIR_ELx register value that the kernel must program to match the
+    /// attribute indices used by [`A64PTE`].
+    ///
+    /// - Attr0 (`Device`): Device-nGnRE memory (`0x04`).
+    /// - Attr1 (`Normal`): Normal memory, write-back non-transient read/write
+    ///   allocate (`0xff`).
+    /// - Attr2 (`NormalNonCacheable`): Normal memory, non-cacheable (`0x44`).
+    pub const MAIR_VALUE: u64 = 0x44_ff_04;
+}
+
+impl From<DescriptorAttr> for MappingFlags {
+    fn from(attr: DescriptorAttr) -> Self {
+        let valid = attr.contains(DescriptorAttr::VALID);
+        let mut flags = Self::empty();
+        if valid {
+            flags |= Self::READ;
+            // without hardware dirty state management, writable pages are
+            // read-only until they are written
+            if !attr.contains(DescriptorAttr::AP_RO) {
+                flags |= Self::WRITE | Self::DIRTY;
+            } else if attr.contains(DescriptorAttr::DBM) {
+                flags |= Self::WRITE;
+            }
+        }
+        if attr.contains(DescriptorAttr::AP_EL0) {
+            flags |= Self::USER;
+            if valid && !attr.contains(DescriptorAttr::UXN) {
+                flags |= Self::EXECUTE;
+            }
+        } else if valid && !attr.contains(DescriptorAttr::PXN) {
+            flags |= Self::EXECUTE;
+        }
+        match attr.mem_attr() {
+            Some(MemAttr::Device) => flags |= Self::DEVICE,
+            Some(MemAttr::NormalNonCacheable) => flags |= Self::UNCACHED,
+            _ => {}
+        }
+        if attr.contains(DescriptorAttr::COW) {
+            flags |= Self::COW;
+        }
+        if attr.contains(DescriptorAttr::SHARED) {
+            flags |= Self::SHARED;
+        }
+        if attr.contains(DescriptorAttr::PINNED) {
+            flags |= Self::PINNED;
+        }
+        if attr.contains(DescriptorAttr::SWAP_BACKED) {
+            flags |= Self::SWAP_BACKED;
+        }
+        if attr.contains(DescriptorAttr::AF) {
+            flags |= Self::ACCESSED;
+        }
+        flags
+    }
+}
+
+impl From<MappingFlags> for DescriptorAttr {
+    fn from(flags: MappingFlags) -> Self {
+        let mut attr = Self::empty();
+        if flags.intersects(MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE) {
+            attr |= Self::VALID;
+        }
+        attr |= if flags.contains(MappingFlags::DEVICE) {
+            Self::from_mem_attr(MemAttr::Device)
+        } else if flags.contains(MappingFlags::UNCACHED) {
+            Self::from_mem_attr(MemAttr::NormalNonCacheable)
+        } else {
+            Self::from_mem_attr(MemAttr::Normal)
+        };
+        if !flags.contains(MappingFlags::WRITE) {
+            attr |= Self::AP_RO;
+        } else if !flags.contains(MappingFlags::DIRTY) {
+            attr |= Self::AP_RO | Self::DBM;
+        }
+        if flags.contains(MappingFlags::USER) {
+            attr |= Self::AP_EL0 | Self::PXN | Self::NG;
+            if !flags.contains(MappingFlags::EXECUTE) {
+                attr |= Self::UXN;
+            }
+        } else {
+            attr |= Self::UXN;
+            if !flags.contains(MappingFlags::EXECUTE) {
+                attr |= Self::PXN;
+            }
+        }
+        if flags.contains(MappingFlags::COW) {
+            attr |= Self::COW;
+        }
+        if flags.contains(MappingFlags::SHARED) {
+            attr |= Self::SHARED;
+        }
+        if flags.contains(MappingFlags::PINNED) {
+            attr |= Self::PINNED;
+        }
+        if flags.contains(MappingFlags::SWAP_BACKED) {
+            attr |= Self::SWAP_BACKED;
+        }
+        if flags.contains(MappingFlags::ACCESSED) {
+            attr |= Self::AF;
+        }
+        attr
+    }
+}
+
+/// AArch64 VMSAv8-64 translation table descriptor for the 4K granule.
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct A64PTE(u64);
+
+impl A64PTE {
+    const PHYS_ADDR_MASK: u64 = 0x0000_ffff_ffff_f000; // bits 12..48
+}
+
+impl GenericPTE for A64PTE {
+    const SOFTWARE_BITS: usize = 4;
+    const HW_DIRTY: bool = false;
+    type Atomic = AtomicU64Entry;
+
+    fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+    fn bits(&self) -> u64 {
+        self.0
+    }
+
+    fn new_page(paddr: PhysAddr, flags: MappingFlags, is_huge: bool) -> Self {
+        let mut attr = DescriptorAttr::from(flags);
+        if !is_huge {
+            attr |= DescriptorAttr::NON_BLOCK;
+        }
+        Self(attr.bits() | (paddr.as_usize() as u64 & Self::PHYS_ADDR_MASK))
+    }
+    fn new_table(paddr: PhysAddr) -> Self {
+        let attr = DescriptorAttr::NON_BLOCK | DescriptorAttr::VALID;
+        Self(attr.bits() | (paddr.as_usize() as u64 & Self::PHYS_ADDR_MASK))
+    }
+    fn paddr(&self) -> PhysAddr {
+        PhysAddr::from((self.0 & Self::PHYS_ADDR_MASK) as usize)
+    }
+    fn flags(&self) -> MappingFlags {
+        DescriptorAttr::from_bits_truncate(self.0).into()
+    }
+    fn set_paddr(&mut self, paddr: PhysAddr) {
+        self.0 =
+            (self.0 & !Self::PHYS_ADDR_MASK) | (paddr.as_usize() as u64 & Self::PHYS_ADDR_MASK);
+    }
+    fn set_flags(&mut self, flags: MappingFlags, is_huge: bool) {
+        let mut attr = DescriptorAttr::from(flags);
+        if !is_huge {
+            attr |= DescriptorAttr::NON_BLOCK;
+        }
+        self.0 = (self.0 & Self::PHYS_ADDR_MASK) | attr.bits();
+    }
+
+    fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+    fn is_present(&self) -> bool {
+        DescriptorAttr::from_bits_truncate(self.0).contains(DescriptorAttr::VALID)
+    }
+    fn is_huge(&self) -> bool {
+        // block descriptors that are not valid still have the attributes set
+        !self.is_unused()
+            && !DescriptorAttr::from_bits_truncate(self.0).contains(DescriptorAttr::NON_BLOCK)
+    }
+    fn clear(&mut self) {
+        self.0 = 0
+    }
+}
+
+impl fmt::Debug for A64PTE {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut f = f.debug_struct("A64PTE");
+        f.field("raw", &self.0)
+            .field("paddr", &self.paddr())
+            .field("attr", &DescriptorAttr::from_bits_truncate(self.0))
+            .field("flags", &self.flags())
+            .finish()
+    }
+}
@@ -0,0 +1,235 @@
+//! LoongArch64 page table entries.
+
+use core::fmt;
+
+use memory_addr::PhysAddr;
+
+use super::{AtomicU64Entry, GenericPTE, MappingFlags};
+
+bitflags::bitflags! {
+    /// Page-table entry flags.
+    pub struct PTEFlags: u64 {
+        /// Whether the PTE is valid.
+        const V =       1 << 0;
+        /// Whether the page is writable and has been written (dirty).
+        const D =       1 << 1;
+        /// Privilege level field, low bit.
+        const PLVL =    1 << 2;
+        /// Privilege level field, high bit.
+        const PLVH =    1 << 3;
+        /// Memory access type field, low bit.
+        const MATL =    1 << 4;
+        /// Memory access type field, high bit.
+        const MATH =    1 << 5;
+        /// Designates a global mapping (basic page entries only).
+        const G =       1 << 6;
+        /// Marks a huge page in a directory (non-last level) entry. It shares
+        /// the bit position with `G` of basic page entries.
+        const HUGE =    1 << 6;
+        /// Whether the physical page exists.
+        const P =       1 << 7;
+        /// Whether the page is writable. The MMU only allows writes if `D` is
+        /// set as well, otherwise the first write faults.
+        const W =       1 << 8;
+        /// Software-defined: the page is copy-on-write.
+        const COW =     1 << 9;
+        /// Software-defined: the page is shared.
+        const SHARED =  1 << 10;
+        /// Software-defined: the page is pinned.
+        const PINNED =  1 << 11;
+        /// Designates a global mapping in huge page entries. The global bit is
+        /// relocated here because bit 6 is taken by `HUGE`.
+        const HGLOBAL = 1 << 12;
+        /// Not readable.
+        const NR =      1 << 61;
+        /// Not executable.
+        const NX =      1 << 62;
+        /// Restricted privilege level. If set, the page can only be accessed
+        /// by the privilege level in `PLV`, otherwise by any level not lower
+        /// than `PLV`.
+        const RPLV =    1 << 63;
+    }
+}
+
+impl PTEFlags {
+    /// Strongly-ordered uncached memory access type (`MAT = 0`).
+    const MAT_SUC: Self = Self::empty();
+    /// Coherent cached memory access type (`MAT = 1`).
+    const MAT_CC: Self = Self::MATL;
+    /// Weakly-ordered uncached memory access type (`MAT = 2`).
+    const MAT_WUC: Self = Self::MATH;
+}
+
+impl From<PTEFlags> for MappingFlags {
+    fn from(f: PTEFlags) -> Self {
+        let mut ret = Self::empty();
+        if f.contains(PTEFlags::V) {
+            if !f.contains(PTEFlags::NR) {
+                ret |= Self::READ;
+            }
+            if f.contains(PTEFlags::W) {
+                ret |= Self::WRITE;
+            }
+            if !f.contains(PTEFlags::NX) {
+                ret |= Self::EXECUTE;
+            }
+        }
+        if f.contains(PTEFlags::PLVL | PTEFlags::PLVH) {
+            ret |= Self::USER;
+        }
+        let mat = f & (PTEFlags::MATL | PTEFlags::MATH);
+        if mat == PTEFlags::MAT_SUC {
+            ret |= Self::DEVICE;
+        } else if mat == PTEFlags::MAT_WUC {
+            ret |= Self::UNCACHED;
+        }
+        if f.contains(PTEFlags::COW) {
+            ret |= Self::COW;
+        }
+        if f.contains(PTEFlags::SHARED) {
+            ret |= Self::SHARED;
+        }
+        if f.contains(PTEFlags::PINNED) {
+            ret |= Self::PINNED;
+        }
+        // there is no accessed bit, and the dirty bit is set by software
+        ret |= Self::ACCESSED;
+        if f.contains(PTEFlags::D) {
+            ret |= Self::DIRTY;
+        }
+        ret
+    }
+}
+
+impl From<MappingFlags> for PTEFlags {
+    fn from(f: MappingFlags) -> Self {
+        let mut ret = Self::P;
+        if f.intersects(MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE) {
+            ret |= Self::V;
+        }
+        if !f.contains(MappingFlags::READ) {
+            ret |= Self::NR;
+        }
+        if f.contains(MappingFlags::WRITE) {
+            ret |= Self::W;
+            if f.contains(MappingFlags::DIRTY) {
+                ret |= Self::D;
+            }
+        }
+        if !f.contains(MappingFlags::EXECUTE) {
+            ret |= Self::NX;
+        }
+        if f.contains(MappingFlags::USER) {
+            ret |= Self::PLVL | Self::PLVH;
+        }
+        if f.contains(MappingFlags::DEVICE) {
+            ret |= Self::MAT_SUC;
+        } else if f.contains(MappingFlags::UNCACHED) {
+            ret |= Self::MAT_WUC;
+        } else {
+            ret |= Self::MAT_CC;
+        }
+        if f.contains(MappingFlags::COW) {
+            ret |= Self::COW;
+        }
+        if f.contains(MappingFlags::SHARED) {
+            ret |= Self::SHARED;
+        }
+        if f.contains(MappingFlags::PINNED) {
+            ret |= Self::PINNED;
+        }
+        ret
+    }
+}
+
+/// LoongArch64 page table entry.
+///
+/// Huge pages are encoded with [`PTEFlags::HUGE`] set in the directory entry,
+/// whose global bit then lives at [`PTEFlags::HGLOBAL`] instead of
+/// [`PTEFlags::G`], so bit 12 is not part of a huge page's physical address.
+/// [`MappingFlags`] cannot express global mappings, so basic page entries are
+/// never global and bit 6 of an entry always means [`PTEFlags::HUGE`].
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct LA64PTE(u64);
+
+impl LA64PTE {
+    const PHYS_ADDR_MASK: u64 = 0x0000_ffff_ffff_f000; // bits 12..48
+
+    /// Returns the bit mask of the physical address, excluding the relocated
+    /// global bit of huge pages.
+    const fn phys_addr_mask(is_huge: bool) -> u64 {
+        if is_huge {
+            Self::PHYS_ADDR_MASK & !PTEFlags::HGLOBAL.bits()
+        } else {
+            Self::PHYS_ADDR_MASK
+        }
+    }
+}
+
+impl GenericPTE for LA64PTE {
+    const SOFTWARE_BITS: usize = 3;
+    const HW_DIRTY: bool = false;
+    type Atomic = AtomicU64Entry;
+
+    fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+    fn bits(&self) -> u64 {
+        self.0
+    }
+
+    fn new_page(paddr: PhysAddr, flags: MappingFlags, is_huge: bool) -> Self {
+        let mut flags = PTEFlags::from(flags);
+        if is_huge {
+            flags |= PTEFlags::HUGE;
+        }
+        Self(flags.bits() | (paddr.as_usize() as u64 & Self::phys_addr_mask(is_huge)))
+    }
+    fn new_table(paddr: PhysAddr) -> Self {
+        Self(PTEFlags::V.bits() | (paddr.as_usize() as u64 & Self::PHYS_ADDR_MASK))
+    }
+    fn paddr(&self) -> PhysAddr {
+        let is_huge = PTEFlags::from_bits_truncate(self.0).contains(PTEFlags::HUGE);
+        PhysAddr::from((self.0 & Self::phys_addr_mask(is_huge)) as usize)
+    }
+    fn flags(&self) -> MappingFlags {
+        PTEFlags::from_bits_truncate(self.0).into()
+    }
+    fn set_paddr(&mut self, paddr: PhysAddr) {
+        let is_huge = PTEFlags::from_bits_truncate(self.0).contains(PTEFlags::HUGE);
+        let mask = Self::phys_addr_mask(is_huge);
+        self.0 = (self.0 & !mask) | (paddr.as_usize() as u64 & mask);
+    }
+    fn set_flags(&mut self, flags: MappingFlags, is_huge: bool) {
+        let mut flags = PTEFlags::from(flags);
+        if is_huge {
+            // keep the relocated global bit of huge pages
+            flags |= PTEFlags::HUGE | (PTEFlags::from_bits_truncate(self.0) & PTEFlags::HGLOBAL);
+        }
+        self.0 = (self.0 & Self::phys_addr_mask(is_huge)) | flags.bits();
+    }
+
+    fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+    fn is_present(&self) -> bool {
+        PTEFlags::from_bits_truncate(self.0).contains(PTEFlags::V)
+    }
+    fn is_huge(&self) -> bool {
+        PTEFlags::from_bits_truncate(self.0).contains(PTEFlags::HUGE)
+    }
+    fn clear(&mut self) {
+        self.0 = 0
+    }
+}
+
+impl fmt::Debug for LA64PTE {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut f = f.debug_struct("LA64PTE");
+        f.field("raw", &self.0)
+            .field("paddr", &self.paddr())
+            .field("flags", &self.flags())
+            .finish()
+    }
+}
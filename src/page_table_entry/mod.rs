@@ -1,9 +1,19 @@
 use core::fmt::Debug;
+use core::sync::atomic::{AtomicU32, Ordering};
 
 use memory_addr::PhysAddr;
 
+#[doc(cfg(target_arch = "aarch64"))]
+pub mod aarch64;
+#[doc(cfg(target_arch = "loongarch64"))]
+pub mod loongarch64;
 #[doc(cfg(any(target_arch = "riscv32", target_arch = "riscv64")))]
 pub mod riscv;
+#[doc(cfg(target_arch = "x86_64"))]
+pub mod x86_64;
+
+#[cfg(test)]
+mod tests;
 
 bitflags::bitflags! {
     /// Generic page table entry flags that indicate the corresponding mapped
@@ -21,6 +31,44 @@ bitflags::bitflags! {
         const DEVICE        = 1 << 4;
         /// The memory is uncached.
         const UNCACHED      = 1 << 5;
+        /// The memory is copy-on-write. It is kept in a bit reserved for
+        /// software, and the hardware does not interpret it.
+        const COW           = 1 << 6;
+        /// The memory has been accessed since the bit was last cleared.
+        /// Architectures without an accessed bit always report it.
+        const ACCESSED      = 1 << 7;
+        /// The memory has been written since the bit was last cleared.
+        /// Architectures whose MMU does not set it write-protect the writable
+        /// memory without it, see [`GenericPTE::HW_DIRTY`].
+        const DIRTY         = 1 << 8;
+        /// Software-defined: the memory is shared with other address spaces.
+        const SHARED        = 1 << 9;
+        /// Software-defined: the memory must not be swapped out or migrated.
+        const PINNED        = 1 << 10;
+        /// Software-defined: the memory is backed by the swap space.
+        const SWAP_BACKED   = 1 << 11;
+    }
+}
+
+impl MappingFlags {
+    /// The flags kept in the bits reserved for software, in the order they
+    /// are assigned to the bits supported by a [`GenericPTE`].
+    const SOFTWARE_ORDER: [Self; 4] = [Self::COW, Self::SHARED, Self::PINNED, Self::SWAP_BACKED];
+
+    /// Returns the software-defined flags that can be stored in a page table
+    /// entry with `count` software bits (see [`GenericPTE::SOFTWARE_BITS`]).
+    ///
+    /// The flags are assigned in the order [`MappingFlags::COW`],
+    /// [`MappingFlags::SHARED`], [`MappingFlags::PINNED`] and
+    /// [`MappingFlags::SWAP_BACKED`].
+    pub const fn software(count: usize) -> Self {
+        let mut flags = Self::empty();
+        let mut i = 0;
+        while i < count && i < Self::SOFTWARE_ORDER.len() {
+            flags = flags.union(Self::SOFTWARE_ORDER[i]);
+            i += 1;
+        }
+        flags
     }
 }
 
@@ -28,6 +76,35 @@ bitflags::bitflags! {
 ///
 /// All architecture-specific page table entry types implement this trait.
 pub trait GenericPTE: Debug + Clone + Copy + Sync + Send + Sized {
+    /// The number of bits reserved for software in a leaf entry, which store
+    /// the flags returned by [`MappingFlags::software`]. Mapping with the
+    /// other software-defined flags fails with
+    /// [`PagingError::InvalidPermission`](crate::PagingError::InvalidPermission).
+    ///
+    /// The bits are kept in leaf entries that are not present as well, e.g.
+    /// those of pages without any access permission or swapped out.
+    ///
+    /// The default is `0`, which supports no software-defined flags.
+    const SOFTWARE_BITS: usize = 0;
+    /// Whether the MMU sets the dirty bit of a leaf entry when the page is
+    /// written. Without it, writable pages whose dirty bit is clear are
+    /// read-only for the MMU, so the first write faults, and the fault
+    /// handler should set the bit with
+    /// [`PageTable64::resolve_dirty_fault`](crate::PageTable64::resolve_dirty_fault).
+    const HW_DIRTY: bool = true;
+
+    /// The atomic storage of the entry in a table.
+    type Atomic: AtomicEntry;
+
+    /// Creates an entry from its raw value.
+    fn from_bits(bits: <Self::Atomic as AtomicEntry>::Bits) -> Self;
+    /// Returns the raw value of the entry.
+    fn bits(&self) -> <Self::Atomic as AtomicEntry>::Bits;
+    /// Creates an unused entry, whose raw value is zero.
+    fn empty() -> Self {
+        Self::from_bits(Default::default())
+    }
+
     /// Creates a page table entry point to a terminate page or block.
     fn new_page(paddr: PhysAddr, flags: MappingFlags, is_huge: bool) -> Self;
     /// Creates a page table entry point to a next level page table.
@@ -48,8 +125,152 @@ pub trait GenericPTE: Debug + Clone + Copy + Sync + Send + Sized {
     /// Returns whether this entry flag indicates present.
     fn is_present(&self) -> bool;
     /// For non-last level translation, returns whether this entry maps to a
-    /// huge frame.
+    /// huge frame, including the huge pages that are not present.
     fn is_huge(&self) -> bool;
     /// Set this entry to zero.
     fn clear(&mut self);
+
+    /// Loads the entry stored in `slot`.
+    fn load(slot: &Self::Atomic) -> Self {
+        Self::from_bits(slot.load_bits())
+    }
+
+    /// Stores `entry` into `slot`.
+    ///
+    /// The previous value is overwritten, so it must only be used on entries
+    /// that the MMU does not update, e.g. those that are not present.
+    fn store(slot: &Self::Atomic, entry: Self) {
+        slot.store_bits(entry.bits());
+    }
+
+    /// Replaces the entry in `slot` with `new` if it still equals `current`,
+    /// atomically against the MMU that may set the accessed and dirty bits
+    /// concurrently.
+    ///
+    /// Returns the previous value, which is `Ok` if the entry is replaced.
+    fn compare_exchange(slot: &Self::Atomic, current: Self, new: Self) -> Result<Self, Self> {
+        slot.compare_exchange_bits(current.bits(), new.bits())
+            .map(Self::from_bits)
+            .map_err(Self::from_bits)
+    }
+
+    /// Replaces the entry in `slot` with the value returned by `f` by
+    /// [`GenericPTE::compare_exchange`], retrying with the latest value if the
+    /// entry is changed concurrently.
+    ///
+    /// Returns the value that `f` is finally applied to, so bits set by the
+    /// MMU during the change are not lost.
+    fn fetch_update<F: FnMut(Self) -> Self>(slot: &Self::Atomic, mut f: F) -> Self {
+        let mut current = Self::load(slot);
+        loop {
+            match Self::compare_exchange(slot, current, f(current)) {
+                Ok(old) => return old,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+/// The atomic storage of a page table entry in a table.
+///
+/// The page table only accesses the entries through it, since the MMU may set
+/// the accessed and dirty bits of an entry concurrently.
+pub trait AtomicEntry: Send + Sync {
+    /// The raw value of the entry.
+    type Bits: Copy + Eq + Default;
+
+    /// Loads the raw value.
+    fn load_bits(&self) -> Self::Bits;
+    /// Stores the raw value.
+    fn store_bits(&self, bits: Self::Bits);
+    /// Stores `new` if the raw value still equals `current`.
+    ///
+    /// Returns the previous value, which is `Ok` if `new` is stored.
+    fn compare_exchange_bits(
+        &self,
+        current: Self::Bits,
+        new: Self::Bits,
+    ) -> Result<Self::Bits, Self::Bits>;
+}
+
+impl AtomicEntry for AtomicU32 {
+    type Bits = u32;
+
+    fn load_bits(&self) -> u32 {
+        self.load(Ordering::Acquire)
+    }
+    fn store_bits(&self, bits: u32) {
+        self.store(bits, Ordering::Release)
+    }
+    #[cfg(target_has_atomic = "32")]
+    fn compare_exchange_bits(&self, current: u32, new: u32) -> Result<u32, u32> {
+        self.compare_exchange(current, new, Ordering::AcqRel, Ordering::Acquire)
+    }
+    #[cfg(not(target_has_atomic = "32"))]
+    fn compare_exchange_bits(&self, current: u32, new: u32) -> Result<u32, u32> {
+        compare_exchange_plain(self, current, new)
+    }
+}
+
+/// The storage of 64-bit page table entries.
+#[cfg(target_has_atomic = "64")]
+pub type AtomicU64Entry = core::sync::atomic::AtomicU64;
+
+#[cfg(target_has_atomic = "64")]
+impl AtomicEntry for AtomicU64Entry {
+    type Bits = u64;
+
+    fn load_bits(&self) -> u64 {
+        self.load(Ordering::Acquire)
+    }
+    fn store_bits(&self, bits: u64) {
+        self.store(bits, Ordering::Release)
+    }
+    fn compare_exchange_bits(&self, current: u64, new: u64) -> Result<u64, u64> {
+        self.compare_exchange(current, new, Ordering::AcqRel, Ordering::Acquire)
+    }
+}
+
+/// The storage of 64-bit page table entries.
+///
+/// 64-bit entries are not walked by the MMU of targets without 64-bit atomic
+/// operations, so they are kept in two 32-bit halves (the low half first)
+/// that are not updated atomically together.
+#[cfg(not(target_has_atomic = "64"))]
+#[derive(Default)]
+#[repr(C)]
+pub struct AtomicU64Entry {
+    low: AtomicU32,
+    high: AtomicU32,
+}
+
+#[cfg(not(target_has_atomic = "64"))]
+impl AtomicEntry for AtomicU64Entry {
+    type Bits = u64;
+
+    fn load_bits(&self) -> u64 {
+        self.low.load_bits() as u64 | (self.high.load_bits() as u64) << 32
+    }
+    fn store_bits(&self, bits: u64) {
+        self.low.store_bits(bits as u32);
+        self.high.store_bits((bits >> 32) as u32);
+    }
+    fn compare_exchange_bits(&self, current: u64, new: u64) -> Result<u64, u64> {
+        compare_exchange_plain(self, current, new)
+    }
+}
+
+#[cfg(any(not(target_has_atomic = "64"), not(target_has_atomic = "32")))]
+fn compare_exchange_plain<A: AtomicEntry>(
+    slot: &A,
+    current: A::Bits,
+    new: A::Bits,
+) -> Result<A::Bits, A::Bits> {
+    let bits = slot.load_bits();
+    if bits == current {
+        slot.store_bits(new);
+        Ok(current)
+    } else {
+        Err(bits)
+    }
 }
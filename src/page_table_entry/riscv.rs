@@ -1,10 +1,11 @@
 //! RISC-V page table entries.
 
 use core::fmt;
+use core::sync::atomic::AtomicU32;
 
 use memory_addr::PhysAddr;
 
-use super::{GenericPTE, MappingFlags};
+use super::{AtomicU64Entry, GenericPTE, MappingFlags};
 
 bitflags::bitflags! {
     /// Page-table entry flags.
@@ -27,34 +28,55 @@ bitflags::bitflags! {
         /// Indicates the virtual page has been written since the last time the
         /// D bit was cleared.
         const D =   1 << 7;
+        /// Software-defined (in the RSW field): the page is copy-on-write.
+        const COW = 1 << 8;
+        /// Software-defined (in the RSW field): the page is shared.
+        const SHARED = 1 << 9;
     }
 }
 
 impl From<PTEFlags> for MappingFlags {
     fn from(f: PTEFlags) -> Self {
         let mut ret = Self::empty();
-        if f.contains(PTEFlags::R) {
-            ret |= Self::READ;
-        }
-        if f.contains(PTEFlags::W) {
-            ret |= Self::WRITE;
-        }
-        if f.contains(PTEFlags::X) {
-            ret |= Self::EXECUTE;
+        if f.contains(PTEFlags::V) {
+            if f.contains(PTEFlags::R) {
+                ret |= Self::READ;
+            }
+            if f.contains(PTEFlags::W) {
+                ret |= Self::WRITE;
+            }
+            if f.contains(PTEFlags::X) {
+                ret |= Self::EXECUTE;
+            }
         }
         if f.contains(PTEFlags::U) {
             ret |= Self::USER;
         }
+        if f.contains(PTEFlags::COW) {
+            ret |= Self::COW;
+        }
+        if f.contains(PTEFlags::SHARED) {
+            ret |= Self::SHARED;
+        }
+        if f.contains(PTEFlags::A) {
+            ret |= Self::ACCESSED;
+        }
+        if f.contains(PTEFlags::D) {
+            ret |= Self::DIRTY;
+        }
         ret
     }
 }
 
 impl From<MappingFlags> for PTEFlags {
     fn from(f: MappingFlags) -> Self {
-        if f.is_empty() {
-            return Self::empty();
+        let mut ret = Self::empty();
+        if f.intersects(MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE) {
+            ret |= Self::V;
+        } else {
+            // `R` without `V` marks a leaf entry that is not present
+            ret |= Self::R;
         }
-        let mut ret = Self::V;
         if f.contains(MappingFlags::READ) {
             ret |= Self::R;
         }
@@ -67,11 +89,23 @@ impl From<MappingFlags> for PTEFlags {
         if f.contains(MappingFlags::USER) {
             ret |= Self::U;
         }
+        if f.contains(MappingFlags::COW) {
+            ret |= Self::COW;
+        }
+        if f.contains(MappingFlags::SHARED) {
+            ret |= Self::SHARED;
+        }
+        if f.contains(MappingFlags::ACCESSED) {
+            ret |= Self::A;
+        }
+        if f.contains(MappingFlags::DIRTY) {
+            ret |= Self::D;
+        }
         ret
     }
 }
 
-/// Sv39 and Sv48 page table entry for RV64 systems.
+/// Sv39, Sv48 and Sv57 page table entry for RV64 systems.
 #[derive(Clone, Copy)]
 #[repr(transparent)]
 pub struct Rv64PTE(u64);
@@ -81,8 +115,18 @@ impl Rv64PTE {
 }
 
 impl GenericPTE for Rv64PTE {
+    const SOFTWARE_BITS: usize = 2;
+    type Atomic = AtomicU64Entry;
+
+    fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+    fn bits(&self) -> u64 {
+        self.0
+    }
+
     fn new_page(paddr: PhysAddr, flags: MappingFlags, _is_huge: bool) -> Self {
-        let flags = PTEFlags::from(flags) | PTEFlags::A | PTEFlags::D;
+        let flags = PTEFlags::from(flags);
         debug_assert!(flags.intersects(PTEFlags::R | PTEFlags::X));
         Self(flags.bits() as u64 | ((paddr.as_usize() >> 2) as u64 & Self::PHYS_ADDR_MASK))
     }
@@ -100,7 +144,7 @@ impl GenericPTE for Rv64PTE {
             | ((paddr.as_usize() as u64 >> 2) & Self::PHYS_ADDR_MASK);
     }
     fn set_flags(&mut self, flags: MappingFlags, _is_huge: bool) {
-        let flags = PTEFlags::from(flags) | PTEFlags::A | PTEFlags::D;
+        let flags = PTEFlags::from(flags);
         debug_assert!(flags.intersects(PTEFlags::R | PTEFlags::X));
         self.0 = (self.0 & Self::PHYS_ADDR_MASK) | flags.bits() as u64;
     }
@@ -128,3 +172,77 @@ impl fmt::Debug for Rv64PTE {
             .finish()
     }
 }
+
+/// Sv32 page table entry for RV32 systems.
+///
+/// The PPN field holds a 34-bit physical address, but only the part that fits
+/// into `usize` can be expressed with [`PhysAddr`] on 32-bit targets.
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct Rv32PTE(u32);
+
+impl Rv32PTE {
+    const PHYS_ADDR_MASK: u32 = !((1 << 10) - 1); // bits 10..32
+
+    fn ppn_bits(paddr: PhysAddr) -> u32 {
+        ((paddr.as_usize() as u64 >> 2) as u32) & Self::PHYS_ADDR_MASK
+    }
+}
+
+impl GenericPTE for Rv32PTE {
+    const SOFTWARE_BITS: usize = 2;
+    type Atomic = AtomicU32;
+
+    fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+    fn bits(&self) -> u32 {
+        self.0
+    }
+
+    fn new_page(paddr: PhysAddr, flags: MappingFlags, _is_huge: bool) -> Self {
+        let flags = PTEFlags::from(flags);
+        debug_assert!(flags.intersects(PTEFlags::R | PTEFlags::X));
+        Self(flags.bits() as u32 | Self::ppn_bits(paddr))
+    }
+    fn new_table(paddr: PhysAddr) -> Self {
+        Self(PTEFlags::V.bits() as u32 | Self::ppn_bits(paddr))
+    }
+    fn paddr(&self) -> PhysAddr {
+        PhysAddr::from((((self.0 & Self::PHYS_ADDR_MASK) as u64) << 2) as usize)
+    }
+    fn flags(&self) -> MappingFlags {
+        PTEFlags::from_bits_truncate(self.0 as usize).into()
+    }
+    fn set_paddr(&mut self, paddr: PhysAddr) {
+        self.0 = (self.0 & !Self::PHYS_ADDR_MASK) | Self::ppn_bits(paddr);
+    }
+    fn set_flags(&mut self, flags: MappingFlags, _is_huge: bool) {
+        let flags = PTEFlags::from(flags);
+        debug_assert!(flags.intersects(PTEFlags::R | PTEFlags::X));
+        self.0 = (self.0 & Self::PHYS_ADDR_MASK) | flags.bits() as u32;
+    }
+
+    fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+    fn is_present(&self) -> bool {
+        PTEFlags::from_bits_truncate(self.0 as usize).contains(PTEFlags::V)
+    }
+    fn is_huge(&self) -> bool {
+        PTEFlags::from_bits_truncate(self.0 as usize).intersects(PTEFlags::R | PTEFlags::X)
+    }
+    fn clear(&mut self) {
+        self.0 = 0
+    }
+}
+
+impl fmt::Debug for Rv32PTE {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut f = f.debug_struct("Rv32PTE");
+        f.field("raw", &self.0)
+            .field("paddr", &self.paddr())
+            .field("flags", &self.flags())
+            .finish()
+    }
+}
@@ -0,0 +1,153 @@
+use super::aarch64::{DescriptorAttr, MemAttr, A64PTE};
+use super::loongarch64::{PTEFlags as LA64Flags, LA64PTE};
+use super::x86_64::{PTEFlags as X64Flags, X64PTE};
+use super::{GenericPTE, MappingFlags};
+use crate::mock::pa;
+
+const RW: MappingFlags = MappingFlags::READ.union(MappingFlags::WRITE);
+const AD: MappingFlags = MappingFlags::ACCESSED.union(MappingFlags::DIRTY);
+
+#[test]
+fn x86_64_entries() {
+    let pte = X64PTE::new_page(pa(0xf_ffff_ffff_f000), RW | MappingFlags::USER | AD, false);
+    let bits = X64Flags::P | X64Flags::RW | X64Flags::US | X64Flags::A | X64Flags::D | X64Flags::NX;
+    assert_eq!(pte.bits(), 0xf_ffff_ffff_f000 | bits.bits());
+    assert_eq!(pte.paddr(), pa(0xf_ffff_ffff_f000));
+    assert_eq!(pte.flags(), RW | MappingFlags::USER | AD);
+    assert!(pte.is_present() && !pte.is_huge());
+
+    let flags = MappingFlags::READ | MappingFlags::EXECUTE | MappingFlags::DEVICE;
+    let pte = X64PTE::new_page(pa(0x4000_0000), flags, true);
+    let bits = X64Flags::P | X64Flags::PCD | X64Flags::PWT | X64Flags::PS;
+    assert_eq!(pte.bits(), 0x4000_0000 | bits.bits());
+    assert_eq!(pte.flags(), flags);
+    assert!(pte.is_huge());
+
+    let pte = X64PTE::new_page(
+        pa(0x1000),
+        MappingFlags::READ | MappingFlags::UNCACHED,
+        false,
+    );
+    assert_eq!(
+        pte.bits() & (X64Flags::PCD | X64Flags::PWT).bits(),
+        X64Flags::PCD.bits()
+    );
+    assert_eq!(pte.flags(), MappingFlags::READ | MappingFlags::UNCACHED);
+
+    // intermediate entries leave the permissions to the leaf entries
+    let pte = X64PTE::new_table(pa(0x2000));
+    assert_eq!(
+        pte.bits(),
+        0x2000 | (X64Flags::P | X64Flags::RW | X64Flags::US).bits()
+    );
+    assert!(pte.is_present() && !pte.is_huge());
+}
+
+#[test]
+fn aarch64_descriptors() {
+    let pte = A64PTE::new_page(pa(0xffff_ffff_f000), RW | AD, false);
+    let attr = DescriptorAttr::VALID
+        | DescriptorAttr::NON_BLOCK
+        | DescriptorAttr::from_mem_attr(MemAttr::Normal)
+        | DescriptorAttr::AF
+        | DescriptorAttr::UXN
+        | DescriptorAttr::PXN;
+    assert_eq!(pte.bits(), 0xffff_ffff_f000 | attr.bits());
+    assert_eq!(pte.paddr(), pa(0xffff_ffff_f000));
+    assert_eq!(pte.flags(), RW | AD);
+    assert!(pte.is_present() && !pte.is_huge());
+
+    // user code is not executable by the kernel
+    let flags = MappingFlags::READ | MappingFlags::EXECUTE | MappingFlags::USER;
+    let pte = A64PTE::new_page(pa(0x1000), flags | MappingFlags::ACCESSED, false);
+    let attr = DescriptorAttr::from_bits_truncate(pte.bits());
+    assert!(attr.contains(
+        DescriptorAttr::AP_EL0 | DescriptorAttr::AP_RO | DescriptorAttr::PXN | DescriptorAttr::NG
+    ));
+    assert!(!attr.contains(DescriptorAttr::UXN));
+    assert_eq!(pte.flags(), flags | MappingFlags::ACCESSED);
+
+    // blocks with the device and non-cacheable memory attributes
+    let flags = MappingFlags::READ | MappingFlags::DEVICE;
+    let pte = A64PTE::new_page(pa(0x4000_0000), flags, true);
+    let attr = DescriptorAttr::from_bits_truncate(pte.bits());
+    assert_eq!(attr.mem_attr(), Some(MemAttr::Device));
+    assert!(!attr.contains(DescriptorAttr::SHAREABLE));
+    assert_eq!(pte.flags(), flags);
+    assert!(pte.is_huge());
+    let pte = A64PTE::new_page(
+        pa(0x20_0000),
+        MappingFlags::READ | MappingFlags::UNCACHED,
+        true,
+    );
+    let attr = DescriptorAttr::from_bits_truncate(pte.bits());
+    assert_eq!(attr.mem_attr(), Some(MemAttr::NormalNonCacheable));
+    assert_eq!(pte.flags(), MappingFlags::READ | MappingFlags::UNCACHED);
+
+    // the MAIR value matches the attribute indices
+    for (attr, value) in [
+        (MemAttr::Device, 0x04),
+        (MemAttr::Normal, 0xff),
+        (MemAttr::NormalNonCacheable, 0x44),
+    ] {
+        assert_eq!((MemAttr::MAIR_VALUE >> (attr as u64 * 8)) & 0xff, value);
+    }
+
+    let pte = A64PTE::new_table(pa(0x2000));
+    let attr = DescriptorAttr::VALID | DescriptorAttr::NON_BLOCK;
+    assert_eq!(pte.bits(), 0x2000 | attr.bits());
+    assert!(pte.is_present() && !pte.is_huge());
+}
+
+#[test]
+fn loongarch64_entries() {
+    let pte = LA64PTE::new_page(pa(0xffff_ffff_f000), RW | AD | MappingFlags::USER, false);
+    let flags = LA64Flags::V
+        | LA64Flags::P
+        | LA64Flags::W
+        | LA64Flags::D
+        | LA64Flags::PLVL
+        | LA64Flags::PLVH
+        | LA64Flags::MATL
+        | LA64Flags::NX;
+    assert_eq!(pte.bits(), 0xffff_ffff_f000 | flags.bits());
+    assert_eq!(pte.paddr(), pa(0xffff_ffff_f000));
+    assert_eq!(pte.flags(), RW | AD | MappingFlags::USER);
+    assert!(pte.is_present() && !pte.is_huge());
+
+    // execute-only device memory
+    let pte = LA64PTE::new_page(
+        pa(0x1000),
+        MappingFlags::EXECUTE | MappingFlags::DEVICE,
+        false,
+    );
+    let flags = LA64Flags::V | LA64Flags::P | LA64Flags::NR;
+    assert_eq!(pte.bits(), 0x1000 | flags.bits());
+    let pte = LA64PTE::new_page(
+        pa(0x1000),
+        MappingFlags::READ | MappingFlags::UNCACHED,
+        false,
+    );
+    let flags = LA64Flags::V | LA64Flags::P | LA64Flags::MATH | LA64Flags::NX;
+    assert_eq!(pte.bits(), 0x1000 | flags.bits());
+    assert_eq!(
+        pte.flags(),
+        MappingFlags::READ | MappingFlags::UNCACHED | MappingFlags::ACCESSED
+    );
+
+    // the global bit of huge pages is relocated to bit 12, outside the address
+    let mut pte = LA64PTE::new_page(pa(0x20_0000), MappingFlags::READ, true);
+    assert!(pte.is_huge());
+    pte = LA64PTE::from_bits(pte.bits() | LA64Flags::HGLOBAL.bits());
+    assert_eq!(pte.paddr(), pa(0x20_0000));
+    pte.set_paddr(pa(0x40_0000));
+    pte.set_flags(RW, true);
+    assert_eq!(pte.paddr(), pa(0x40_0000));
+    let flags = LA64Flags::from_bits_truncate(pte.bits());
+    assert!(flags.contains(LA64Flags::HUGE | LA64Flags::HGLOBAL | LA64Flags::W));
+    assert!(pte.is_huge());
+
+    let pte = LA64PTE::new_table(pa(0x2000));
+    assert_eq!(pte.bits(), 0x2000 | LA64Flags::V.bits());
+    assert!(pte.is_present() && !pte.is_huge());
+}
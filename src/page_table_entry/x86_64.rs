@@ -0,0 +1,202 @@
+//! x86_64 page table entries.
+
+use core::fmt;
+
+use memory_addr::PhysAddr;
+
+use super::{AtomicU64Entry, GenericPTE, MappingFlags};
+
+bitflags::bitflags! {
+    /// Page-table entry flags.
+    pub struct PTEFlags: u64 {
+        /// Whether the PTE is present.
+        const P =       1 << 0;
+        /// Whether the memory is writable.
+        const RW =      1 << 1;
+        /// Whether the memory is accessible to user mode.
+        const US =      1 << 2;
+        /// Page-level write-through.
+        const PWT =     1 << 3;
+        /// Page-level cache disable.
+        const PCD =     1 << 4;
+        /// Indicates whether software has accessed the memory.
+        const A =       1 << 5;
+        /// Indicates whether software has written to the memory.
+        const D =       1 << 6;
+        /// For non-last level entries, maps a 1G or 2M huge page.
+        const PS =      1 << 7;
+        /// Designates a global mapping, which is not flushed on CR3 reload.
+        const G =       1 << 8;
+        /// Software-defined: the page is copy-on-write.
+        const COW =     1 << 9;
+        /// Software-defined: the page is shared.
+        const SHARED =  1 << 10;
+        /// Software-defined: the page is pinned.
+        const PINNED =  1 << 11;
+        /// Software-defined: the page is backed by the swap space.
+        const SWAP_BACKED = 1 << 52;
+        /// Forbids instruction fetches from the memory.
+        const NX =      1 << 63;
+    }
+}
+
+impl From<PTEFlags> for MappingFlags {
+    fn from(f: PTEFlags) -> Self {
+        let mut ret = Self::empty();
+        if f.contains(PTEFlags::P) {
+            ret |= Self::READ;
+            if f.contains(PTEFlags::RW) {
+                ret |= Self::WRITE;
+            }
+            if !f.contains(PTEFlags::NX) {
+                ret |= Self::EXECUTE;
+            }
+        }
+        if f.contains(PTEFlags::US) {
+            ret |= Self::USER;
+        }
+        if f.contains(PTEFlags::PCD) {
+            if f.contains(PTEFlags::PWT) {
+                ret |= Self::DEVICE;
+            } else {
+                ret |= Self::UNCACHED;
+            }
+        }
+        if f.contains(PTEFlags::COW) {
+            ret |= Self::COW;
+        }
+        if f.contains(PTEFlags::SHARED) {
+            ret |= Self::SHARED;
+        }
+        if f.contains(PTEFlags::PINNED) {
+            ret |= Self::PINNED;
+        }
+        if f.contains(PTEFlags::SWAP_BACKED) {
+            ret |= Self::SWAP_BACKED;
+        }
+        if f.contains(PTEFlags::A) {
+            ret |= Self::ACCESSED;
+        }
+        if f.contains(PTEFlags::D) {
+            ret |= Self::DIRTY;
+        }
+        ret
+    }
+}
+
+impl From<MappingFlags> for PTEFlags {
+    fn from(f: MappingFlags) -> Self {
+        let mut ret = Self::empty();
+        if f.intersects(MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE) {
+            ret |= Self::P;
+        }
+        if f.contains(MappingFlags::WRITE) {
+            ret |= Self::RW;
+        }
+        if !f.contains(MappingFlags::EXECUTE) {
+            ret |= Self::NX;
+        }
+        if f.contains(MappingFlags::USER) {
+            ret |= Self::US;
+        }
+        if f.contains(MappingFlags::DEVICE) {
+            ret |= Self::PCD | Self::PWT;
+        } else if f.contains(MappingFlags::UNCACHED) {
+            ret |= Self::PCD;
+        }
+        if f.contains(MappingFlags::COW) {
+            ret |= Self::COW;
+        }
+        if f.contains(MappingFlags::SHARED) {
+            ret |= Self::SHARED;
+        }
+        if f.contains(MappingFlags::PINNED) {
+            ret |= Self::PINNED;
+        }
+        if f.contains(MappingFlags::SWAP_BACKED) {
+            ret |= Self::SWAP_BACKED;
+        }
+        if f.contains(MappingFlags::ACCESSED) {
+            ret |= Self::A;
+        }
+        if f.contains(MappingFlags::DIRTY) {
+            ret |= Self::D;
+        }
+        ret
+    }
+}
+
+/// x86_64 page table entry.
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct X64PTE(u64);
+
+impl X64PTE {
+    const PHYS_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000; // bits 12..52
+}
+
+impl GenericPTE for X64PTE {
+    const SOFTWARE_BITS: usize = 4;
+    type Atomic = AtomicU64Entry;
+
+    fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+    fn bits(&self) -> u64 {
+        self.0
+    }
+
+    fn new_page(paddr: PhysAddr, flags: MappingFlags, is_huge: bool) -> Self {
+        let mut flags = PTEFlags::from(flags);
+        if is_huge {
+            flags |= PTEFlags::PS;
+        }
+        Self(flags.bits() | (paddr.as_usize() as u64 & Self::PHYS_ADDR_MASK))
+    }
+    fn new_table(paddr: PhysAddr) -> Self {
+        // Permissions of intermediate entries are the most permissive ones, the
+        // leaf entry decides the final permission.
+        let flags = PTEFlags::P | PTEFlags::RW | PTEFlags::US;
+        Self(flags.bits() | (paddr.as_usize() as u64 & Self::PHYS_ADDR_MASK))
+    }
+    fn paddr(&self) -> PhysAddr {
+        PhysAddr::from((self.0 & Self::PHYS_ADDR_MASK) as usize)
+    }
+    fn flags(&self) -> MappingFlags {
+        PTEFlags::from_bits_truncate(self.0).into()
+    }
+    fn set_paddr(&mut self, paddr: PhysAddr) {
+        self.0 =
+            (self.0 & !Self::PHYS_ADDR_MASK) | (paddr.as_usize() as u64 & Self::PHYS_ADDR_MASK);
+    }
+    fn set_flags(&mut self, flags: MappingFlags, is_huge: bool) {
+        let mut flags = PTEFlags::from(flags);
+        if is_huge {
+            flags |= PTEFlags::PS;
+        }
+        self.0 = (self.0 & Self::PHYS_ADDR_MASK) | flags.bits();
+    }
+
+    fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+    fn is_present(&self) -> bool {
+        PTEFlags::from_bits_truncate(self.0).contains(PTEFlags::P)
+    }
+    fn is_huge(&self) -> bool {
+        PTEFlags::from_bits_truncate(self.0).contains(PTEFlags::PS)
+    }
+    fn clear(&mut self) {
+        self.0 = 0
+    }
+}
+
+impl fmt::Debug for X64PTE {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut f = f.debug_struct("X64PTE");
+        f.field("raw", &self.0)
+            .field("paddr", &self.paddr())
+            .field("flags", &self.flags())
+            .finish()
+    }
+}
@@ -1,6 +1,18 @@
 //! RISC-V specific page table structures.
 
-use crate::{page_table_entry::riscv::Rv64PTE, PageTable64, PagingMetaData};
+use crate::{
+    page_table_entry::riscv::{Rv32PTE, Rv64PTE},
+    PageSize, PageTable32, PageTable64, PagingMetaData, TrackedTables,
+};
+
+/// Encodes the `satp` register of RV64 with the given MODE field.
+const fn satp64(mode: u64, root_paddr: usize, asid: u16) -> usize {
+    (mode << 60 | (asid as u64) << 44 | (root_paddr as u64 >> 12)) as usize
+}
+
+/// Metadata of RISC-V Sv32 page tables.
+#[derive(Clone, Copy)]
+pub struct Sv32MetaData;
 
 /// Metadata of RISC-V Sv39 page tables.
 #[derive(Clone, Copy)]
@@ -10,20 +22,82 @@ pub struct Sv39MetaData;
 #[derive(Clone, Copy)]
 pub struct Sv48MetaData;
 
+/// Metadata of RISC-V Sv57 page tables.
+#[derive(Clone, Copy)]
+pub struct Sv57MetaData;
+
+impl const PagingMetaData for Sv32MetaData {
+    const LEVELS: usize = 2;
+    const PA_MAX_BITS: usize = 34;
+    const VA_MAX_BITS: usize = 32;
+    const MAX_PAGE_SIZE: PageSize = PageSize::Size4M;
+    // Physical addresses above 4G cannot be expressed on 32-bit targets.
+    const PA_MAX_ADDR: usize = ((1u64 << Self::PA_MAX_BITS) - 1) as usize;
+
+    #[inline]
+    fn vaddr_is_valid(vaddr: usize) -> bool {
+        // Sv32 translates the whole 32-bit address space.
+        vaddr as u64 <= u32::MAX as u64
+    }
+
+    #[inline]
+    fn vaddr_range_is_valid(start: usize, last: usize) -> bool {
+        start <= last && last as u64 <= u32::MAX as u64
+    }
+
+    #[inline]
+    fn root_register(root_paddr: usize, asid: u16) -> usize {
+        // MODE (bit 31) = 1, ASID in bits 22..31, PPN in bits 0..22. Sv32
+        // ASIDs are 9 bits wide, the higher bits of `asid` are ignored.
+        1 << 31 | (asid as usize & 0x1ff) << 22 | (root_paddr >> 12) & 0x3f_ffff
+    }
+}
+
 impl const PagingMetaData for Sv39MetaData {
     const LEVELS: usize = 3;
     const PA_MAX_BITS: usize = 56;
     const VA_MAX_BITS: usize = 39;
+
+    #[inline]
+    fn root_register(root_paddr: usize, asid: u16) -> usize {
+        satp64(8, root_paddr, asid)
+    }
 }
 
 impl const PagingMetaData for Sv48MetaData {
     const LEVELS: usize = 4;
     const PA_MAX_BITS: usize = 56;
     const VA_MAX_BITS: usize = 48;
+    #[cfg(target_pointer_width = "64")]
+    const MAX_PAGE_SIZE: PageSize = PageSize::Size512G;
+
+    #[inline]
+    fn root_register(root_paddr: usize, asid: u16) -> usize {
+        satp64(9, root_paddr, asid)
+    }
 }
 
+impl const PagingMetaData for Sv57MetaData {
+    const LEVELS: usize = 5;
+    const PA_MAX_BITS: usize = 56;
+    const VA_MAX_BITS: usize = 57;
+    #[cfg(target_pointer_width = "64")]
+    const MAX_PAGE_SIZE: PageSize = PageSize::Size512G;
+
+    #[inline]
+    fn root_register(root_paddr: usize, asid: u16) -> usize {
+        satp64(10, root_paddr, asid)
+    }
+}
+
+/// Sv32: Page-Based 32-bit (2 levels) Virtual-Memory System.
+pub type Sv32PageTable<I> = PageTable32<Sv32MetaData, Rv32PTE, I>;
+
 /// Sv39: Page-Based 39-bit (3 levels) Virtual-Memory System.
-pub type Sv39PageTable<I> = PageTable64<Sv39MetaData, Rv64PTE, I>;
+pub type Sv39PageTable<I, S = TrackedTables<Rv64PTE>> = PageTable64<Sv39MetaData, Rv64PTE, I, S>;
 
 /// Sv48: Page-Based 48-bit (4 levels) Virtual-Memory System.
-pub type Sv48PageTable<I> = PageTable64<Sv48MetaData, Rv64PTE, I>;
+pub type Sv48PageTable<I, S = TrackedTables<Rv64PTE>> = PageTable64<Sv48MetaData, Rv64PTE, I, S>;
+
+/// Sv57: Page-Based 57-bit (5 levels) Virtual-Memory System.
+pub type Sv57PageTable<I, S = TrackedTables<Rv64PTE>> = PageTable64<Sv57MetaData, Rv64PTE, I, S>;
@@ -0,0 +1,75 @@
+use alloc::{boxed::Box, collections::BTreeMap};
+
+use memory_addr::PhysAddr;
+
+use crate::{GenericPTE, LinearPagingIf, NotLeafPage, PagingError, PagingIf, PagingResult};
+
+/// The storage of the tables of a [`PageTable64`](crate::PageTable64), which
+/// decides how they are allocated with the allocator `IF` and accessed.
+pub trait TableStore<PTE: GenericPTE, IF>: Default {
+    /// Allocates a zeroed table, returns its physical address.
+    fn alloc_table(&mut self, alloc: &mut IF) -> PagingResult<PhysAddr>;
+    /// Releases the table at `paddr`.
+    fn dealloc_table(&mut self, alloc: &mut IF, paddr: PhysAddr);
+    /// Returns the entries of the table at `paddr`.
+    fn table<'a>(&'a self, alloc: &'a IF, paddr: PhysAddr) -> &'a [PTE::Atomic];
+}
+
+/// Tables allocated by [`PagingIf::alloc_frame`], which are tracked by their
+/// physical addresses.
+///
+/// It is the default, and works without a linear mapping of the physical
+/// memory.
+pub struct TrackedTables<PTE: GenericPTE>(BTreeMap<PhysAddr, Box<dyn NotLeafPage<PTE>>>);
+
+impl<PTE: GenericPTE> Default for TrackedTables<PTE> {
+    fn default() -> Self {
+        Self(BTreeMap::new())
+    }
+}
+
+impl<PTE: GenericPTE, IF: PagingIf<PTE>> TableStore<PTE, IF> for TrackedTables<PTE> {
+    fn alloc_table(&mut self, alloc: &mut IF) -> PagingResult<PhysAddr> {
+        let mut page = alloc.alloc_frame().ok_or(PagingError::NoMemory)?;
+        page.zero();
+        let paddr = page.phys_addr();
+        self.0.insert(paddr, page);
+        Ok(paddr)
+    }
+
+    fn dealloc_table(&mut self, alloc: &mut IF, paddr: PhysAddr) {
+        if let Some(page) = self.0.remove(&paddr) {
+            alloc.dealloc_frame(page);
+        }
+    }
+
+    fn table<'a>(&'a self, _alloc: &'a IF, paddr: PhysAddr) -> &'a [PTE::Atomic] {
+        self.0.get(&paddr).unwrap().as_pte_slice()
+    }
+}
+
+/// Tables allocated by [`LinearPagingIf::alloc_table_frame`], and accessed
+/// through [`LinearPagingIf::phys_to_virt`].
+///
+/// Nothing is kept per table, so walking a level costs no lookup.
+#[derive(Default)]
+pub struct LinearTables;
+
+impl<PTE: GenericPTE, IF: LinearPagingIf<PTE>> TableStore<PTE, IF> for LinearTables {
+    fn alloc_table(&mut self, alloc: &mut IF) -> PagingResult<PhysAddr> {
+        let paddr = alloc.alloc_table_frame().ok_or(PagingError::NoMemory)?;
+        alloc
+            .phys_to_virt(paddr)
+            .iter()
+            .for_each(|slot| PTE::store(slot, PTE::empty()));
+        Ok(paddr)
+    }
+
+    fn dealloc_table(&mut self, alloc: &mut IF, paddr: PhysAddr) {
+        alloc.dealloc_table_frame(paddr);
+    }
+
+    fn table<'a>(&'a self, alloc: &'a IF, paddr: PhysAddr) -> &'a [PTE::Atomic] {
+        alloc.phys_to_virt(paddr)
+    }
+}
@@ -0,0 +1,125 @@
+//! TLB invalidation after page table changes.
+
+use core::fmt;
+
+use memory_addr::VirtAddr;
+
+/// The **architecture-dependent** TLB invalidation that must be provided to
+/// consume a [`TlbFlushToken`].
+///
+/// It is implemented by the kernel with `sfence.vma` on RISC-V, `invlpg` on
+/// x86_64, `tlbi vae1is` on AArch64, or `invtlb` on LoongArch64, as these
+/// instructions cannot be issued without unsafe code. The implementor usually
+/// carries the ASID of the address space whose TLB entries are invalidated.
+pub trait TlbFlush {
+    /// Invalidates the TLB entries that map the page containing `vaddr`.
+    fn flush_page(&self, vaddr: VirtAddr);
+    /// Invalidates all TLB entries of the address space.
+    fn flush_all(&self);
+}
+
+/// The TLB invalidation required after page table entries are changed,
+/// returned by the operations that change them.
+///
+/// It records the changed pages, and turns into a flush of the entire TLB
+/// when there are more than [`TlbFlushToken::MAX_PAGES`] of them. Tokens of
+/// batched operations can be combined with [`TlbFlushToken::merge`], so that
+/// the TLB is flushed only once.
+#[must_use = "the changes are not visible to the hardware until the TLB is flushed"]
+pub struct TlbFlushToken {
+    pages: [usize; Self::MAX_PAGES],
+    len: usize,
+    all: bool,
+}
+
+impl TlbFlushToken {
+    /// The maximum number of pages that are flushed one by one.
+    pub const MAX_PAGES: usize = 16;
+
+    /// Creates a token that does not flush anything.
+    pub const fn empty() -> Self {
+        Self {
+            pages: [0; Self::MAX_PAGES],
+            len: 0,
+            all: false,
+        }
+    }
+
+    /// Creates a token that flushes the entire TLB.
+    pub const fn all() -> Self {
+        Self {
+            pages: [0; Self::MAX_PAGES],
+            len: 0,
+            all: true,
+        }
+    }
+
+    /// Creates a token that flushes the page containing `vaddr`.
+    pub(crate) fn page(vaddr: VirtAddr) -> Self {
+        let mut token = Self::empty();
+        token.add(vaddr);
+        token
+    }
+
+    /// Adds the page containing `vaddr` to the pages to flush.
+    pub(crate) fn add(&mut self, vaddr: VirtAddr) {
+        if self.all {
+            return;
+        }
+        if self.len == Self::MAX_PAGES {
+            self.all = true;
+        } else {
+            self.pages[self.len] = vaddr.as_usize();
+            self.len += 1;
+        }
+    }
+
+    /// Combines the pages to flush of `other` into this token.
+    pub fn merge(&mut self, other: Self) {
+        if other.all {
+            self.all = true;
+        }
+        for &vaddr in &other.pages[..other.len] {
+            self.add(VirtAddr::from(vaddr));
+        }
+    }
+
+    /// Whether nothing needs to be flushed.
+    pub const fn is_empty(&self) -> bool {
+        !self.all && self.len == 0
+    }
+
+    /// Whether the entire TLB needs to be flushed.
+    pub const fn is_all(&self) -> bool {
+        self.all
+    }
+
+    /// Returns the pages to flush one by one, which is empty if the entire
+    /// TLB needs to be flushed.
+    pub fn pages(&self) -> impl Iterator<Item = VirtAddr> + '_ {
+        let len = if self.all { 0 } else { self.len };
+        self.pages[..len].iter().map(|&vaddr| VirtAddr::from(vaddr))
+    }
+
+    /// Flushes the TLB with `flusher`.
+    pub fn flush<F: TlbFlush + ?Sized>(self, flusher: &F) {
+        if self.all {
+            flusher.flush_all();
+        } else {
+            self.pages().for_each(|vaddr| flusher.flush_page(vaddr));
+        }
+    }
+
+    /// Discards the token without flushing, e.g. when the page table is not
+    /// active on any CPU.
+    pub fn ignore(self) {}
+}
+
+impl fmt::Debug for TlbFlushToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlbFlushToken")
+            .field("all", &self.all)
+            .field("pages", &&self.pages[..self.len])
+            .finish()
+    }
+}
@@ -0,0 +1,521 @@
+//! The page table walking shared by [`PageTable64`](crate::PageTable64) and
+//! [`PageTable32`](crate::PageTable32).
+
+use memory_addr::{PhysAddr, VirtAddr};
+
+use crate::{
+    GenericPTE, MappingFlags, PageSize, PagingError, PagingMetaData, PagingResult, TlbFlushToken,
+};
+
+/// All page sizes, in which the size mapped by a leaf entry is looked up.
+const PAGE_SIZES: &[PageSize] = &[
+    PageSize::Size4K,
+    PageSize::Size2M,
+    PageSize::Size4M,
+    PageSize::Size1G,
+    #[cfg(target_pointer_width = "64")]
+    PageSize::Size512G,
+];
+
+/// Returns the flags to set on a leaf entry whose current flags are `old`
+/// (empty for a new entry). The accessed and dirty bits are set unless
+/// `track_access` is enabled, in which case those of `old` are kept.
+///
+/// Flags without any access permission are returned unchanged. The leaf
+/// entries with them are encoded not present, so that the MMU faults on any
+/// access, but they keep the target, the other flags and the marker that
+/// [`GenericPTE::is_huge`] recognises, and are never
+/// [unused](GenericPTE::is_unused). The page stays mapped, e.g. as
+/// `PROT_NONE` memory, and can be queried, unmapped or protected again.
+pub(crate) fn leaf_flags(
+    track_access: bool,
+    flags: MappingFlags,
+    old: MappingFlags,
+) -> MappingFlags {
+    let bits = MappingFlags::ACCESSED | MappingFlags::DIRTY;
+    if !flags.intersects(MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE) {
+        flags
+    } else if track_access {
+        flags | (old & bits)
+    } else {
+        flags | bits
+    }
+}
+
+/// A multi-level page table whose tables have `1 << INDEX_BITS` entries and
+/// are accessed by their physical addresses.
+///
+/// The operations that only depend on the table layout are provided on top of
+/// the table accesses, so that the page tables of different entry widths share
+/// them.
+pub(crate) trait PageTableWalk {
+    /// The metadata of the page table.
+    type Meta: PagingMetaData;
+    /// The type of the entries.
+    type Entry: GenericPTE;
+
+    /// The number of virtual address bits that index a table.
+    const INDEX_BITS: usize;
+    /// The number of entries in a table.
+    const ENTRY_COUNT: usize = 1 << Self::INDEX_BITS;
+
+    /// Returns the physical address of the root table.
+    fn root_table(&self) -> PhysAddr;
+    /// Returns the entries of the table at `paddr`.
+    fn table_of(&self, paddr: PhysAddr) -> &[<Self::Entry as GenericPTE>::Atomic];
+    /// Allocates a zeroed table, returns its physical address.
+    fn alloc_table(&mut self) -> PagingResult<PhysAddr>;
+    /// Releases the table at `paddr`.
+    fn dealloc_table(&mut self, paddr: PhysAddr);
+
+    /// Releases the intermediate table at `paddr` that has been unlinked.
+    fn free_table(&mut self, paddr: PhysAddr) {
+        self.dealloc_table(paddr);
+    }
+
+    /// Whether new leaf entries are created with the accessed and dirty bits
+    /// clear.
+    fn track_access(&self) -> bool {
+        false
+    }
+
+    /// Whether `vaddr` is mapped by the tables shared from another page table,
+    /// which cannot be accessed.
+    fn is_shared(&self, _vaddr: VirtAddr) -> bool {
+        false
+    }
+
+    /// Whether the root table entry that maps `vaddr` is shared with other
+    /// page tables, so the table it points to must never be reclaimed.
+    fn is_exported(&self, _vaddr: VirtAddr) -> bool {
+        false
+    }
+
+    /// Loads the entry at `index` of the table at `paddr`.
+    fn entry_at(&self, paddr: PhysAddr, index: usize) -> Self::Entry {
+        Self::Entry::load(&self.table_of(paddr)[index])
+    }
+
+    /// Returns whether all entries of the table at `paddr` are unused.
+    fn table_is_empty(&self, paddr: PhysAddr) -> bool {
+        self.table_of(paddr)
+            .iter()
+            .all(|slot| Self::Entry::load(slot).is_unused())
+    }
+
+    /// Returns the number of address bits translated by the tables below the
+    /// given `level` (the root table is level `0`).
+    fn level_shift(level: usize) -> usize {
+        12 + (Self::Meta::LEVELS - 1 - level) * Self::INDEX_BITS
+    }
+
+    /// Returns the index of the entry that maps `vaddr` in the table of the
+    /// given `level`.
+    fn entry_index(vaddr: VirtAddr, level: usize) -> usize {
+        (vaddr.as_usize() >> Self::level_shift(level)) & (Self::ENTRY_COUNT - 1)
+    }
+
+    /// Returns the size of the page mapped by a leaf entry of the given
+    /// `level`, or `None` if the entries of the level cannot be leaves.
+    fn leaf_page_size(level: usize) -> Option<PageSize> {
+        let shift = Self::level_shift(level);
+        PAGE_SIZES
+            .iter()
+            .copied()
+            .find(|&page_size| (page_size as usize).trailing_zeros() as usize == shift)
+            .filter(|&page_size| page_size as usize <= Self::Meta::MAX_PAGE_SIZE as usize)
+    }
+
+    /// Returns the size of the page mapped by a leaf entry of the given `level`.
+    fn level_page_size(level: usize) -> PageSize {
+        Self::leaf_page_size(level)
+            .unwrap_or_else(|| unreachable!("no leaf entries at level {}", level))
+    }
+
+    /// Returns the level of the leaf entries that map pages of `page_size`, or
+    /// [`PagingError::UnsupportedPageSize`] if they cannot be mapped.
+    fn page_size_level(page_size: PageSize) -> PagingResult<usize> {
+        (0..Self::Meta::LEVELS)
+            .find(|&level| Self::leaf_page_size(level) == Some(page_size))
+            .ok_or(PagingError::UnsupportedPageSize)
+    }
+
+    /// Returns the largest page size to map the region of `size` bytes from
+    /// `vaddr` to `paddr` with, which is 4K unless `allow_huge` is true.
+    fn region_page_size(
+        vaddr: VirtAddr,
+        paddr: PhysAddr,
+        size: usize,
+        allow_huge: bool,
+    ) -> PageSize {
+        if !allow_huge {
+            return PageSize::Size4K;
+        }
+        (0..Self::Meta::LEVELS)
+            .filter_map(Self::leaf_page_size)
+            .find(|&page_size| {
+                vaddr.is_aligned(page_size)
+                    && paddr.is_aligned(page_size)
+                    && size >= page_size as usize
+            })
+            .unwrap_or(PageSize::Size4K)
+    }
+
+    /// Checks that the `size` bytes starting with `vaddr` are valid virtual
+    /// addresses, see [`PagingMetaData::vaddr_range_is_valid`].
+    fn check_vaddr(vaddr: VirtAddr, size: usize) -> PagingResult {
+        let last = vaddr.as_usize().wrapping_add(size.max(1) - 1);
+        Self::check_vaddr_range(vaddr, VirtAddr::from(last))
+    }
+
+    /// Checks that the addresses from `start` to `last` inclusive are valid
+    /// virtual addresses, see [`PagingMetaData::vaddr_range_is_valid`].
+    fn check_vaddr_range(start: VirtAddr, last: VirtAddr) -> PagingResult {
+        let (start, last) = (start.as_usize(), last.as_usize());
+        if !Self::Meta::vaddr_is_valid(start) {
+            Err(PagingError::InvalidAddress(start))
+        } else if last < start || !Self::Meta::vaddr_range_is_valid(start, last) {
+            Err(PagingError::InvalidAddress(last))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks that the `size` bytes starting with `paddr` are valid physical
+    /// addresses.
+    fn check_paddr(paddr: PhysAddr, size: usize) -> PagingResult {
+        let start = paddr.as_usize();
+        let last = start.wrapping_add(size.max(1) - 1);
+        if !Self::Meta::paddr_is_valid(start) {
+            Err(PagingError::InvalidAddress(start))
+        } else if last < start || !Self::Meta::paddr_is_valid(last) {
+            Err(PagingError::InvalidAddress(last))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks that the software-defined flags in `flags` can be stored in the
+    /// entries, see [`GenericPTE::SOFTWARE_BITS`].
+    fn check_flags(flags: MappingFlags) -> PagingResult {
+        let supported = MappingFlags::software(Self::Entry::SOFTWARE_BITS);
+        if flags.intersects(MappingFlags::software(usize::MAX) - supported) {
+            Err(PagingError::InvalidPermission)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the physical address of the next level table pointed to by
+    /// `entry`.
+    fn next_table_paddr(entry: &Self::Entry) -> PagingResult<PhysAddr> {
+        if entry.is_huge() {
+            Err(PagingError::MappedToHugePage)
+        } else if !entry.is_present() {
+            Err(PagingError::NotMapped)
+        } else {
+            Ok(entry.paddr())
+        }
+    }
+
+    /// Returns the physical address of the next level table pointed to by the
+    /// entry at `index` of the table at `paddr`, creating the table if the
+    /// entry is unused.
+    fn next_table_or_create(&mut self, paddr: PhysAddr, index: usize) -> PagingResult<PhysAddr> {
+        let entry = self.entry_at(paddr, index);
+        if entry.is_unused() {
+            let next_paddr = self.alloc_table()?;
+            Self::Entry::store(
+                &self.table_of(paddr)[index],
+                Self::Entry::new_table(next_paddr),
+            );
+            Ok(next_paddr)
+        } else {
+            Self::next_table_paddr(&entry)
+        }
+    }
+
+    /// Returns the physical address of the table of the given `level` on the
+    /// path to `vaddr`, without creating any intermediate tables.
+    fn table_paddr_at(&self, vaddr: VirtAddr, level: usize) -> PagingResult<PhysAddr> {
+        if self.is_shared(vaddr) {
+            return Err(PagingError::SharedMapping);
+        }
+        let mut paddr = self.root_table();
+        for level in 0..level {
+            paddr = Self::next_table_paddr(&self.entry_at(paddr, Self::entry_index(vaddr, level)))?;
+        }
+        Ok(paddr)
+    }
+
+    /// Returns the physical address of the table that holds the leaf entry
+    /// mapping `vaddr`, and the level of the table.
+    fn leaf_table_paddr(&self, vaddr: VirtAddr) -> PagingResult<(PhysAddr, usize)> {
+        if self.is_shared(vaddr) {
+            return Err(PagingError::SharedMapping);
+        }
+        let mut paddr = self.root_table();
+        for level in 0..Self::Meta::LEVELS - 1 {
+            let entry = self.entry_at(paddr, Self::entry_index(vaddr, level));
+            if entry.is_huge() {
+                return Ok((paddr, level));
+            }
+            paddr = Self::next_table_paddr(&entry)?;
+        }
+        Ok((paddr, Self::Meta::LEVELS - 1))
+    }
+
+    /// Returns the leaf entry that maps `vaddr`, and the size of the page it
+    /// maps.
+    fn get_entry(
+        &self,
+        vaddr: VirtAddr,
+    ) -> PagingResult<(&<Self::Entry as GenericPTE>::Atomic, PageSize)> {
+        let (paddr, level) = self.leaf_table_paddr(vaddr)?;
+        let slot = &self.table_of(paddr)[Self::entry_index(vaddr, level)];
+        Ok((slot, Self::level_page_size(level)))
+    }
+
+    /// Returns the entry that maps `vaddr` with a page of `page_size`,
+    /// creating intermediate tables if necessary.
+    fn get_entry_or_create(
+        &mut self,
+        vaddr: VirtAddr,
+        page_size: PageSize,
+    ) -> PagingResult<&<Self::Entry as GenericPTE>::Atomic> {
+        if self.is_shared(vaddr) {
+            return Err(PagingError::SharedMapping);
+        }
+        let target_level = Self::page_size_level(page_size)?;
+        let mut paddr = self.root_table();
+        for level in 0..target_level {
+            paddr = self.next_table_or_create(paddr, Self::entry_index(vaddr, level))?;
+        }
+        Ok(&self.table_of(paddr)[Self::entry_index(vaddr, target_level)])
+    }
+
+    /// Maps a page, see `PageTable64::map`.
+    fn map_page(
+        &mut self,
+        vaddr: VirtAddr,
+        target: PhysAddr,
+        page_size: PageSize,
+        flags: MappingFlags,
+    ) -> PagingResult<TlbFlushToken> {
+        Self::check_vaddr(vaddr.align_down(page_size), page_size as usize)?;
+        Self::check_paddr(target.align_down(page_size), page_size as usize)?;
+        Self::check_flags(flags)?;
+        let flags = leaf_flags(self.track_access(), flags, MappingFlags::empty());
+        let slot = self.get_entry_or_create(vaddr, page_size)?;
+        if !Self::Entry::load(slot).is_unused() {
+            return Err(PagingError::AlreadyMapped);
+        }
+        let entry = Self::Entry::new_page(target.align_down(page_size), flags, page_size.is_huge());
+        Self::Entry::store(slot, entry);
+        Ok(TlbFlushToken::page(vaddr))
+    }
+
+    /// Unmaps a page and reclaims the tables that become empty, see
+    /// `PageTable64::unmap`.
+    fn unmap_page(
+        &mut self,
+        vaddr: VirtAddr,
+    ) -> PagingResult<(Self::Entry, PageSize, TlbFlushToken)> {
+        Self::check_vaddr(vaddr, 1)?;
+        let (paddr, level) = self.leaf_table_paddr(vaddr)?;
+        let size = Self::level_page_size(level);
+        let slot = &self.table_of(paddr)[Self::entry_index(vaddr, level)];
+        if Self::Entry::load(slot).is_unused() {
+            return Err(PagingError::NotMapped);
+        }
+        let old = Self::Entry::fetch_update(slot, |mut entry| {
+            entry.clear();
+            entry
+        });
+        if self.reclaim_tables(vaddr, level) {
+            return Ok((old, size, TlbFlushToken::all()));
+        }
+        Ok((old, size, TlbFlushToken::page(vaddr)))
+    }
+
+    /// Queries a page, see `PageTable64::query`.
+    fn query_page(&self, vaddr: VirtAddr) -> PagingResult<(PhysAddr, MappingFlags, PageSize)> {
+        Self::check_vaddr(vaddr, 1)?;
+        let (slot, size) = self.get_entry(vaddr)?;
+        let entry = Self::Entry::load(slot);
+        if entry.is_unused() {
+            return Err(PagingError::NotMapped);
+        }
+        let off = vaddr.align_offset(size);
+        Ok((entry.paddr() + off, entry.flags(), size))
+    }
+
+    /// Updates a page, see `PageTable64::update`.
+    fn update_page(
+        &mut self,
+        vaddr: VirtAddr,
+        paddr: Option<PhysAddr>,
+        flags: Option<MappingFlags>,
+    ) -> PagingResult<(Self::Entry, PageSize, TlbFlushToken)> {
+        Self::check_vaddr(vaddr, 1)?;
+        if let Some(paddr) = paddr {
+            Self::check_paddr(paddr, 1)?;
+        }
+        if let Some(flags) = flags {
+            Self::check_flags(flags)?;
+        }
+        let track_access = self.track_access();
+        let (slot, size) = self.get_entry(vaddr)?;
+        let old = Self::Entry::fetch_update(slot, |mut entry| {
+            if let Some(paddr) = paddr {
+                entry.set_paddr(paddr);
+            }
+            if let Some(flags) = flags {
+                let flags = leaf_flags(track_access, flags, entry.flags());
+                entry.set_flags(flags, size.is_huge());
+            }
+            entry
+        });
+        Ok((old, size, TlbFlushToken::page(vaddr)))
+    }
+
+    /// Unmaps the pages in `[start, end)` mapped by a failed `map_region`, and
+    /// reclaims the intermediate tables that were allocated to map `end`.
+    ///
+    /// The TLB entries to flush for the unmapped pages and the reclaimed tables
+    /// are added to `flush`.
+    fn rollback_map_region(
+        &mut self,
+        start: VirtAddr,
+        end: VirtAddr,
+        flush: &mut TlbFlushToken,
+    ) -> PagingResult {
+        let mut vaddr = start;
+        while vaddr < end {
+            let (_, page_size, page_flush) = self.unmap_page(vaddr)?;
+            flush.merge(page_flush);
+            vaddr += page_size as usize;
+        }
+        if self.reclaim_tables(end, Self::Meta::LEVELS - 1) {
+            flush.merge(TlbFlushToken::all());
+        }
+        Ok(())
+    }
+
+    /// Reclaims the tables on the path to `vaddr` that are entirely unused,
+    /// starting from the table of the given `level` up to (but excluding) the
+    /// root table. Levels whose tables do not exist on the path are skipped,
+    /// and the tables shared with other page tables are kept.
+    ///
+    /// Returns whether any table is reclaimed, in which case the hardware may
+    /// still cache it until the entire TLB is flushed.
+    fn reclaim_tables(&mut self, vaddr: VirtAddr, level: usize) -> bool {
+        let mut reclaimed = false;
+        for level in (1..=level).rev() {
+            let Ok(parent_paddr) = self.table_paddr_at(vaddr, level - 1) else {
+                continue;
+            };
+            if level == 1 && self.is_exported(vaddr) {
+                break;
+            }
+            let index = Self::entry_index(vaddr, level - 1);
+            let parent = self.entry_at(parent_paddr, index);
+            if !parent.is_present() || parent.is_huge() {
+                continue;
+            }
+            let paddr = parent.paddr();
+            if !self.table_is_empty(paddr) {
+                break;
+            }
+            Self::Entry::store(&self.table_of(parent_paddr)[index], Self::Entry::empty());
+            self.free_table(paddr);
+            reclaimed = true;
+        }
+        reclaimed
+    }
+
+    /// Splits the huge page mapped by the entry at `index` of the table at
+    /// `paddr` of the given `level` into a new table of pages of the next
+    /// smaller size, which map the same physical memory with the same flags.
+    ///
+    /// The huge page is replaced by compare-and-swap, and the new table is
+    /// filled again if the MMU sets the accessed or dirty bit meanwhile, so
+    /// the bits are not lost.
+    ///
+    /// Returns the physical address of the new table.
+    fn split_huge(
+        &mut self,
+        paddr: PhysAddr,
+        index: usize,
+        level: usize,
+    ) -> PagingResult<PhysAddr> {
+        let page_size = Self::level_page_size(level + 1);
+        let table_paddr = self.alloc_table()?;
+        let slot = &self.table_of(paddr)[index];
+        let mut entry = Self::Entry::load(slot);
+        loop {
+            debug_assert!(level < Self::Meta::LEVELS - 1 && entry.is_huge());
+            let (target, flags) = (entry.paddr(), entry.flags());
+            for (i, sub_slot) in self.table_of(table_paddr).iter().enumerate() {
+                let sub_entry = Self::Entry::new_page(
+                    target + i * page_size as usize,
+                    flags,
+                    page_size.is_huge(),
+                );
+                Self::Entry::store(sub_slot, sub_entry);
+            }
+            match Self::Entry::compare_exchange(slot, entry, Self::Entry::new_table(table_paddr)) {
+                Ok(_) => return Ok(table_paddr),
+                Err(actual) => entry = actual,
+            }
+        }
+    }
+
+    /// Releases the table at `paddr` of the given `level` and all tables
+    /// under it, except the tables shared from another page table.
+    fn free_tables_recursive(&mut self, paddr: PhysAddr, level: usize, start_vaddr: VirtAddr) {
+        if level < Self::Meta::LEVELS - 1 {
+            for i in 0..Self::ENTRY_COUNT {
+                let entry = self.entry_at(paddr, i);
+                let vaddr = start_vaddr + (i << Self::level_shift(level));
+                if entry.is_present() && !entry.is_huge() && !self.is_shared(vaddr) {
+                    self.free_tables_recursive(entry.paddr(), level + 1, vaddr);
+                }
+            }
+        }
+        self.dealloc_table(paddr);
+    }
+
+    /// Walks the table at `paddr` of the given `level` recursively, see
+    /// `PageTable64::walk`.
+    fn walk_recursive<F>(
+        &self,
+        paddr: PhysAddr,
+        level: usize,
+        start_vaddr: VirtAddr,
+        limit: usize,
+        func: &F,
+    ) -> PagingResult
+    where
+        F: Fn(usize, usize, VirtAddr, &Self::Entry),
+    {
+        let mut n = 0;
+        for (i, slot) in self.table_of(paddr).iter().enumerate() {
+            let vaddr = start_vaddr + (i << Self::level_shift(level));
+            let entry = Self::Entry::load(slot);
+            if !entry.is_unused() {
+                func(level, i, vaddr, &entry);
+                // tables shared from another page table are not tracked
+                if level < Self::Meta::LEVELS - 1 && !entry.is_huge() && !self.is_shared(vaddr) {
+                    let next_paddr = Self::next_table_paddr(&entry)?;
+                    self.walk_recursive(next_paddr, level + 1, vaddr, limit, func)?;
+                }
+                n += 1;
+                if n >= limit {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+//! x86 specific page table structures.
+
+use crate::{page_table_entry::x86_64::X64PTE, PageTable64, PagingMetaData, TrackedTables};
+
+/// Metadata of x86_64 page tables.
+#[derive(Clone, Copy)]
+pub struct X64PagingMetaData;
+
+/// Metadata of x86_64 5-level page tables (`CR4.LA57` enabled).
+#[derive(Clone, Copy)]
+pub struct X64Level5MetaData;
+
+/// Encodes `CR3` with the PCID in bits 0..12, which requires `CR4.PCIDE`
+/// unless `pcid` is `0`.
+///
+/// PCIDs are 12 bits wide, the higher bits of `pcid` are ignored.
+const fn cr3(root_paddr: usize, pcid: u16) -> usize {
+    const PCID_MASK: usize = (1 << 12) - 1;
+    (root_paddr & !PCID_MASK) | (pcid as usize & PCID_MASK)
+}
+
+impl const PagingMetaData for X64PagingMetaData {
+    const LEVELS: usize = 4;
+    const PA_MAX_BITS: usize = 52;
+    const VA_MAX_BITS: usize = 48;
+
+    #[inline]
+    fn root_register(root_paddr: usize, asid: u16) -> usize {
+        cr3(root_paddr, asid)
+    }
+}
+
+impl const PagingMetaData for X64Level5MetaData {
+    const LEVELS: usize = 5;
+    const PA_MAX_BITS: usize = 52;
+    // Canonical addresses have bits 63..57 equal to bit 56.
+    const VA_MAX_BITS: usize = 57;
+
+    #[inline]
+    fn root_register(root_paddr: usize, asid: u16) -> usize {
+        cr3(root_paddr, asid)
+    }
+}
+
+/// x86_64 page table.
+pub type X64PageTable<I, S = TrackedTables<X64PTE>> = PageTable64<X64PagingMetaData, X64PTE, I, S>;
+
+/// x86_64 5-level page table, for CPUs running with `CR4.LA57` enabled.
+pub type X64Level5PageTable<I, S = TrackedTables<X64PTE>> =
+    PageTable64<X64Level5MetaData, X64PTE, I, S>;